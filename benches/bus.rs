@@ -0,0 +1,61 @@
+use std::time::Instant;
+
+use rustygb::{Cartridge, FrameBuffer, Hardware, System};
+
+struct Headless;
+
+impl Hardware for Headless {
+    fn is_active(&mut self) -> bool {
+        true
+    }
+    fn draw_framebuffer(&mut self, _frame_buffer: &FrameBuffer) {}
+    fn update(&mut self) {}
+}
+
+// A loop that touches ROM, WRAM, HRAM, VRAM and I/O on every iteration.
+const PROGRAM: [u8; 24] = [
+    0x21, 0x00, 0xC0, // ld hl, $C000
+    0xFA, 0x00, 0x40, // loop: ld a, [$4000]
+    0x22, //             ld [hl+], a
+    0xE0, 0x90, //       ldh [$FF90], a
+    0xF0, 0x90, //       ldh a, [$FF90]
+    0xF0, 0x44, //       ldh a, [$FF44]
+    0xEA, 0x00, 0x80, // ld [$8000], a
+    0x7C, //             ld a, h
+    0xE6, 0xDF, //       and $DF
+    0x67, //             ld h, a
+    0xC3, 0x03, 0x01, // jp loop
+    0x00,
+];
+
+fn bench(name: &str, program: &[u8], instructions: u32) {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    let mut system = System::new(Cartridge::new(rom, vec![0; 0x2000]), Headless);
+
+    let start = Instant::now();
+    for _ in 0..instructions {
+        system.step().unwrap();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{:<12} {:>10.0} instructions/s ({:.1}x realtime)",
+        name,
+        instructions as f64 / elapsed,
+        system.cycles() as f64 / elapsed / 4_194_304.0
+    );
+}
+
+// `cargo bench --bench bus`, three interleaved runs each on the same machine,
+// on the commit that introduced the page table and on its parent, which
+// still dispatched through a HashMap per address (`Headless` given that
+// tree's `get_keys`):
+//
+//                HashMap bus       page table
+//   memory mix   8.2-9.8M/s        12.3-13.3M/s
+//   rom spin     10.7-12.2M/s      15.6-17.5M/s
+fn main() {
+    bench("memory mix", &PROGRAM, 20_000_000);
+    // jr -2 spins on a single ROM read per instruction.
+    bench("rom spin", &[0x18, 0xFE], 20_000_000);
+}
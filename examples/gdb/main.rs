@@ -0,0 +1,40 @@
+extern crate rustygb;
+use rustygb::{Cartridge, FrameBuffer, GdbStub, Hardware, System};
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+
+const ADDRESS: &str = "127.0.0.1:2345";
+
+// Runs without a window; the debugger is the only way to look at the game.
+struct Headless;
+
+impl Hardware for Headless {
+    fn is_active(&mut self) -> bool {
+        true
+    }
+    fn draw_framebuffer(&mut self, _frame_buffer: &FrameBuffer) {}
+    fn update(&mut self) {}
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        panic!("usage: gdb [FileName]");
+    }
+
+    let rom = fs::read(&args[1]).expect("File Not Found");
+    let cartridge = Cartridge::new(rom, vec![0; 0x8000]);
+    let mut system = System::new(cartridge, Headless);
+
+    let listener = TcpListener::bind(ADDRESS).expect("cannot listen");
+    println!("waiting for gdb on {} (target remote {})", ADDRESS, ADDRESS);
+    let (stream, _) = listener.accept().expect("cannot accept");
+    stream.set_nodelay(true).ok();
+
+    if let Err(e) = GdbStub::new(stream).serve(&mut system) {
+        eprintln!("rustygb: {}", e);
+        std::process::exit(1);
+    }
+}
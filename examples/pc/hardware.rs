@@ -1,11 +1,9 @@
 use minifb::{Key, Scale, Window, WindowOptions};
-use rustygb::{FrameBuffer, Pixel};
+use rustygb::{Button, FrameBuffer, JoypadState, Pixel};
 
 pub struct Hardware {
     window: Window,
-    cross_button: u8,
-    ab_button: u8,
-    count: i32,
+    keys: JoypadState,
 }
 
 impl Hardware {
@@ -25,25 +23,24 @@ impl Hardware {
         });
         Hardware {
             window: window,
-            cross_button: 0,
-            ab_button: 0,
-            count: 0,
+            keys: JoypadState::new(),
         }
     }
 
+    // The window only collects key events when it is redrawn, so the keys
+    // are read once per frame and handed out on every poll.
     fn update_keys(&mut self) {
-        self.cross_button = 0;
-        self.ab_button = 0;
+        self.keys = JoypadState::new();
         for key in self.window.get_keys() {
             match key {
-                Key::Up => self.cross_button |= 0x04,
-                Key::Down => self.cross_button |= 0x08,
-                Key::Left => self.cross_button |= 0x02,
-                Key::Right => self.cross_button |= 0x01,
-                Key::Z => self.ab_button |= 0x01,
-                Key::X => self.ab_button |= 0x02,
-                Key::A => self.ab_button |= 0x08,
-                Key::B => self.ab_button |= 0x04,
+                Key::Up => self.keys.press(Button::Up),
+                Key::Down => self.keys.press(Button::Down),
+                Key::Left => self.keys.press(Button::Left),
+                Key::Right => self.keys.press(Button::Right),
+                Key::Z => self.keys.press(Button::A),
+                Key::X => self.keys.press(Button::B),
+                Key::A => self.keys.press(Button::Start),
+                Key::B => self.keys.press(Button::Select),
                 _ => {}
             }
         }
@@ -68,18 +65,12 @@ impl rustygb::Hardware for Hardware {
         self.window
             .update_with_buffer(&frame, rustygb::FRAME_WIDTH, rustygb::FRAME_HEIGHT)
             .unwrap();
+        self.update_keys();
     }
 
-    fn get_keys(&mut self) -> (u8, u8) {
-        (self.cross_button, self.ab_button)
+    fn get_keys(&mut self) -> Option<JoypadState> {
+        Some(self.keys)
     }
 
-    fn update(&mut self) {
-        if self.count == 0xFF {
-            self.count = 0;
-            self.update_keys();
-        } else {
-            self.count += 1;
-        }
-    }
+    fn update(&mut self) {}
 }
@@ -28,5 +28,8 @@ fn main() {
     let rom = read_rom(&args[1]);
     let cartridge = Cartridge::new(rom, vec![0; 0x8000]);
 
-    rustygb::run(cartridge, hw);
+    if let Err(e) = rustygb::run(cartridge, hw) {
+        eprintln!("rustygb: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,77 @@
+extern crate rustygb;
+use rustygb::{Cartridge, FrameBuffer, Hardware, Symbols, System};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+// Writes a gameboy-doctor log of the first frames of a ROM. With --doctor,
+// LY always reads $90 as the reference logs assume, so the log can be diffed
+// against them line for line. Otherwise, given a .sym file, each line also
+// gets the label of PC as a comment.
+struct Headless;
+
+impl Hardware for Headless {
+    fn is_active(&mut self) -> bool {
+        true
+    }
+    fn draw_framebuffer(&mut self, _frame_buffer: &FrameBuffer) {}
+    fn update(&mut self) {}
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let doctor = match args.iter().position(|arg| arg == "--doctor") {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    };
+    if args.len() < 3 {
+        panic!("usage: trace [--doctor] [FileName] [LogName] [Frames] [SymName]");
+    }
+    let frames: u64 = match args.get(3) {
+        Some(frames) => frames.parse().expect("Frames must be a number"),
+        None => 60,
+    };
+
+    let rom = fs::read(&args[1]).expect("File Not Found");
+    let mut system = System::new(Cartridge::new(rom, vec![0; 0x8000]), Headless);
+    system.set_doctor_mode(doctor);
+
+    // Labels would break the diff against reference logs.
+    let symbols = match args.get(4) {
+        Some(_) if doctor => {
+            eprintln!("trace: ignoring symbols in doctor mode");
+            None
+        }
+        Some(name) => Some(Symbols::parse(
+            &fs::read_to_string(name).expect("File Not Found"),
+        )),
+        None => None,
+    };
+
+    let mut log = BufWriter::new(File::create(&args[2]).expect("cannot create log"));
+    system.set_trace(move |entry| {
+        let written = match &symbols {
+            Some(symbols) => writeln!(
+                log,
+                "{} ; {}",
+                entry,
+                symbols.describe(entry.bank, entry.cpu.pc)
+            ),
+            None => writeln!(log, "{}", entry),
+        };
+        written.expect("cannot write log");
+    });
+
+    while system.frames() < frames {
+        if let Err(e) = system.run_frame() {
+            eprintln!("rustygb: {}", e);
+            break;
+        }
+    }
+    // Dropping the hook flushes the log.
+    system.clear_trace();
+}
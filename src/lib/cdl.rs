@@ -0,0 +1,148 @@
+use alloc::vec::Vec;
+use core::{cell::Cell, fmt};
+
+// Flags per ROM byte. Code and data use the same bits as Mesen's Game Boy
+// .cdl files; operands are only told apart in memory, see `to_bytes`.
+// First byte of an executed instruction.
+pub const CDL_CODE: u8 = 0x01;
+// Read by a load or by DMA.
+pub const CDL_DATA: u8 = 0x02;
+// Any other byte fetched as part of an instruction.
+pub const CDL_OPERAND: u8 = 0x10;
+
+// Code/data log over the physical ROM, so the same address in two banks is
+// logged separately.
+pub struct CodeDataLog {
+    flags: Vec<Cell<u8>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    pub size: usize,
+    pub code: usize,
+    pub operand: usize,
+    pub data: usize,
+    // Bytes with any flag set.
+    pub covered: usize,
+}
+
+impl Coverage {
+    pub fn percent(&self, count: usize) -> f32 {
+        match self.size {
+            0 => 0.0,
+            size => count as f32 * 100.0 / size as f32,
+        }
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "code {:.2}%, operand {:.2}%, data {:.2}%, covered {:.2}% of {} bytes",
+            self.percent(self.code),
+            self.percent(self.operand),
+            self.percent(self.data),
+            self.percent(self.covered),
+            self.size
+        )
+    }
+}
+
+impl CodeDataLog {
+    pub fn new(size: usize) -> CodeDataLog {
+        CodeDataLog {
+            flags: (0..size).map(|_| Cell::new(0)).collect(),
+        }
+    }
+
+    // Continues a log saved with `to_bytes` or by Mesen. Operands come back
+    // as code, and flags other than code and data are dropped.
+    pub fn from_bytes(bytes: &[u8]) -> CodeDataLog {
+        CodeDataLog {
+            flags: bytes
+                .iter()
+                .map(|flags| Cell::new(flags & (CDL_CODE | CDL_DATA)))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.flags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    pub fn mark(&self, offset: usize, flag: u8) {
+        if let Some(flags) = self.flags.get(offset) {
+            flags.set(flags.get() | flag);
+        }
+    }
+
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).map_or(0, Cell::get)
+    }
+
+    pub fn clear(&self) {
+        self.flags.iter().for_each(|flags| flags.set(0));
+    }
+
+    // One byte of flags per ROM byte, in the Mesen layout: operands are
+    // marked as code, as they are there.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.flags
+            .iter()
+            .map(Cell::get)
+            .map(|flags| match flags & CDL_OPERAND {
+                0 => flags,
+                _ => flags & !CDL_OPERAND | CDL_CODE,
+            })
+            .collect()
+    }
+
+    pub fn coverage(&self) -> Coverage {
+        let mut coverage = Coverage {
+            size: self.flags.len(),
+            code: 0,
+            operand: 0,
+            data: 0,
+            covered: 0,
+        };
+        for flags in self.flags.iter().map(Cell::get) {
+            coverage.code += (flags & CDL_CODE != 0) as usize;
+            coverage.operand += (flags & CDL_OPERAND != 0) as usize;
+            coverage.data += (flags & CDL_DATA != 0) as usize;
+            coverage.covered += (flags != 0) as usize;
+        }
+        coverage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn coverage_and_bytes() {
+        let cdl = CodeDataLog::new(8);
+        cdl.mark(0, CDL_CODE);
+        cdl.mark(1, CDL_OPERAND);
+        cdl.mark(1, CDL_DATA);
+        cdl.mark(7, CDL_DATA);
+        cdl.mark(8, CDL_CODE);
+        assert_eq!(
+            cdl.coverage().to_string(),
+            "code 12.50%, operand 12.50%, data 25.00%, covered 37.50% of 8 bytes"
+        );
+        assert_eq!(cdl.to_bytes(), [1, 3, 0, 0, 0, 0, 0, 2]);
+        // Reloaded, the operand is code; Mesen's jump target flag is dropped.
+        let mut bytes = cdl.to_bytes();
+        bytes[2] = 0x04;
+        let coverage = CodeDataLog::from_bytes(&bytes).coverage();
+        assert_eq!((coverage.code, coverage.operand), (2, 0));
+        assert_eq!((coverage.data, coverage.covered), (2, 3));
+    }
+}
@@ -1,35 +1,203 @@
+use alloc::boxed::Box;
+
+use crate::cdl::{CDL_CODE, CDL_DATA, CDL_OPERAND};
+use crate::debug::{CallStack, Frame, FrameKind, History, TraceEntry};
+use crate::error::{EmuError, ErrorKind};
 use crate::inst::{
     inst_cb_time, inst_time, Condition, Instruction, Operand, Reg16Index, Reg8Index,
 };
 use crate::mmu::MemoryBus;
 use crate::register::{Flag, Registers};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 const IO_IF: usize = 0x0F;
 
+pub type TraceHook = Box<dyn FnMut(&TraceEntry)>;
+
+// A copy of the CPU registers for the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    pub halted: bool,
+}
+
 pub struct CPU {
     reg: Registers,
     cycles: u16,
     IME: bool,
     halt: bool,
+    // EI takes effect after the instruction that follows it.
+    ei_delay: bool,
+    // HALT with an interrupt already pending does not halt, and the next
+    // opcode fetch fails to increment PC.
+    halt_bug: bool,
+    // STOP mode: everything but the joypad is halted until a button is pressed.
+    stopped: bool,
+    // Set by an undefined opcode. Only a reset gets the CPU out of it.
+    locked: bool,
+    // In accurate mode every bus access and internal delay advances the
+    // scheduler by one M-cycle as it happens, instead of the whole
+    // instruction being accounted for at the end.
+    accurate: bool,
+    ticked: u16,
+    trace: Option<TraceHook>,
+    calls: CallStack,
+    history: History,
 }
 
+const HISTORY_LEN: usize = 32;
+
 impl CPU {
     pub fn new() -> CPU {
         CPU {
             reg: Registers::new(),
             cycles: 0,
-            IME: true,
+            IME: false,
             halt: false,
+            ei_delay: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+            accurate: false,
+            ticked: 0,
+            trace: None,
+            calls: CallStack::new(),
+            history: History::new(HISTORY_LEN),
+        }
+    }
+    pub fn pc(&self) -> u16 {
+        self.reg.pc
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.reg.sp
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.reg.a,
+            f: self.reg.f,
+            b: self.reg.b,
+            c: self.reg.c,
+            d: self.reg.d,
+            e: self.reg.e,
+            h: self.reg.h,
+            l: self.reg.l,
+            sp: self.reg.sp,
+            pc: self.reg.pc,
+            ime: self.IME,
+            halted: self.halt,
+        }
+    }
+
+    // The low nibble of F does not exist on hardware and is dropped.
+    pub fn set_state(&mut self, state: CpuState) {
+        self.reg.a = state.a;
+        self.reg.f = state.f & 0xF0;
+        self.reg.b = state.b;
+        self.reg.c = state.c;
+        self.reg.d = state.d;
+        self.reg.e = state.e;
+        self.reg.h = state.h;
+        self.reg.l = state.l;
+        self.reg.sp = state.sp;
+        self.reg.pc = state.pc;
+        self.IME = state.ime;
+        self.ei_delay = false;
+        self.halt = state.halted;
+        self.halt_bug = false;
+    }
+
+    // False while halted, stopped or locked up, when no instruction runs.
+    pub fn is_running(&self) -> bool {
+        !self.halt && !self.stopped && !self.locked
+    }
+
+    pub fn calls(&self) -> &CallStack {
+        &self.calls
+    }
+
+    pub fn calls_mut(&mut self) -> &mut CallStack {
+        &mut self.calls
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    // An error at `pc`, with the call stack and history attached.
+    pub fn error(&self, kind: ErrorKind, pc: u16) -> EmuError {
+        EmuError {
+            kind,
+            pc,
+            call_stack: self.calls.frames().to_vec(),
+            history: self.history.iter().copied().collect(),
+            warnings: self.calls.warnings().to_vec(),
+        }
+    }
+
+    pub fn set_trace(&mut self, trace: Option<TraceHook>) {
+        self.trace = trace;
+    }
+
+    fn emit_trace(&mut self, bus: &MemoryBus) {
+        let pc = self.reg.pc;
+        let entry = TraceEntry {
+            cpu: self.state(),
+            pcmem: [0, 1, 2, 3].map(|offset| bus.peek(pc.wrapping_add(offset))),
+            bank: bus.rom_bank_at(0x4000).unwrap(),
+        };
+        if let Some(trace) = self.trace.as_mut() {
+            trace(&entry);
         }
     }
+
+    // The next step dispatches an interrupt instead of running an instruction.
+    pub fn interrupt_due(&self, bus: &MemoryBus) -> bool {
+        !self.stopped && !self.locked && self.IME && self.pending_interrupts(bus) != 0
+    }
+
+    pub fn set_accurate(&mut self, accurate: bool) {
+        self.accurate = accurate;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn wake(&mut self) {
+        self.stopped = false;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
     fn execute(&mut self, bus: &mut MemoryBus, instruction: Instruction) -> u16 {
         match instruction {
             // Single Inst
             Instruction::NOP => {}
-            Instruction::HALT => self.halt = true,
-            Instruction::STOP => {}
-            Instruction::DI => self.IME = false,
-            Instruction::EI => self.IME = true,
+            Instruction::HALT => self.halt(bus),
+            Instruction::STOP => self.stop(bus),
+            Instruction::DI => {
+                self.IME = false;
+                self.ei_delay = false;
+            }
+            Instruction::EI => self.ei_delay = !self.IME,
 
             // Branch / Function Inst
             Instruction::JR(cond) => {
@@ -43,7 +211,8 @@ impl CPU {
                 };
                 if branch {
                     self.reg.pc = self.reg.pc.wrapping_add(dest as u16);
-                    // self.tick();
+                    self.taken(cond, 1);
+                    self.tick(bus);
                 }
             }
             Instruction::JP(cond) => {
@@ -57,11 +226,15 @@ impl CPU {
                 };
                 if branch {
                     self.reg.pc = dest;
-                    // self.tick();
+                    self.taken(cond, 1);
+                    self.tick(bus);
                 }
             }
             Instruction::JPHL => self.reg.pc = self.reg.hl(),
             Instruction::RET(cond) => {
+                if !matches!(cond, Condition::ALWAYS) {
+                    self.tick(bus);
+                }
                 let branch = match cond {
                     Condition::ALWAYS => true,
                     Condition::NZ => !self.reg.zero(),
@@ -70,15 +243,15 @@ impl CPU {
                     Condition::C => self.reg.carry(),
                 };
                 if branch {
-                    self.reg.pc = self.pop(bus);
-                    // self.tick();
+                    self.ret(bus);
+                    self.taken(cond, 3);
+                    self.tick(bus);
                 }
-                // self.tick();
             }
             Instruction::RETI => {
                 self.IME = true;
-                self.reg.pc = self.pop(bus);
-                // self.tick()
+                self.ret(bus);
+                self.tick(bus);
             }
             Instruction::CALL(cond) => {
                 let dest = self.read_word_pc(bus);
@@ -90,9 +263,11 @@ impl CPU {
                     Condition::C => self.reg.carry(),
                 };
                 if branch {
-                    self.push(bus, self.reg.pc);
+                    let ret = self.reg.pc;
+                    self.push(bus, ret);
                     self.reg.pc = dest;
-                    // self.tick();
+                    self.taken(cond, 3);
+                    self.enter(bus, FrameKind::Call, ret.wrapping_sub(3), ret);
                 }
             }
             Instruction::PUSH(op) => {
@@ -120,7 +295,7 @@ impl CPU {
                 self.write_operand16(bus, dest, value);
                 if let Operand::Register16(Reg16Index::SP) = dest {
                     if let Operand::Register16(Reg16Index::HL) = src {
-                        // self.tick();
+                        self.tick(bus);
                     }
                 }
             }
@@ -136,7 +311,7 @@ impl CPU {
                         self.reg.set_flag(Flag::H, half_carry);
                         self.reg.set_flag(Flag::C, carry);
                         self.reg.set_hl(new_value);
-                        // self.tick();
+                        self.tick(bus);
                     }
                     Operand::Register8(Reg8Index::A) => {
                         let value = value.wrapping_add(0xFF00);
@@ -156,7 +331,7 @@ impl CPU {
                 match op {
                     Operand::Register16(_) => {
                         self.write_operand16(bus, op, value.wrapping_add(1));
-                        // self.tick();
+                        self.tick(bus);
                     }
                     Operand::Register8(_) => {
                         let value = (value as u8).wrapping_add(1);
@@ -173,7 +348,7 @@ impl CPU {
                 match op {
                     Operand::Register16(_) => {
                         self.write_operand16(bus, op, value.wrapping_sub(1));
-                        // self.tick();
+                        self.tick(bus);
                     }
                     Operand::Register8(_) => {
                         let value = (value as u8).wrapping_sub(1);
@@ -195,7 +370,7 @@ impl CPU {
                     .set_flag(Flag::H, (value & 0xFFF) + (self.reg.hl() & 0xFFF) > 0xFFF);
                 self.reg.set_flag(Flag::C, carry);
                 self.reg.set_hl(new_value);
-                // self.tick();
+                self.tick(bus);
             }
             Instruction::ADDSP => {
                 let value = self.fetch(bus) as i8 as i16 as u16;
@@ -438,8 +613,10 @@ impl CPU {
 
             // Etc
             Instruction::RST(addr) => {
-                self.push(bus, self.reg.pc);
+                let ret = self.reg.pc;
+                self.push(bus, ret);
                 self.reg.pc = addr as u16;
+                self.enter(bus, FrameKind::Rst, ret.wrapping_sub(1), ret);
             }
             Instruction::DAA => {
                 let (mut offset, mut carry) = (0 as u8, false);
@@ -464,16 +641,43 @@ impl CPU {
         self.reg.pc
     }
 
+    // One M-cycle passes. Only accurate mode moves time forward here; the
+    // rest of the instruction is accounted for at the end of `step`.
+    fn tick(&mut self, bus: &MemoryBus) {
+        if self.accurate {
+            bus.scheduler().advance(4);
+            self.ticked += 1;
+        }
+    }
+
+    // The timing tables hold the length of a conditional instruction when
+    // the branch is not taken.
+    fn taken(&mut self, cond: Condition, extra: u16) {
+        if !matches!(cond, Condition::ALWAYS) {
+            self.cycles += extra;
+        }
+    }
+
     fn read_byte(&mut self, bus: &mut MemoryBus, address: u16) -> u8 {
-        // self.tick();
-        bus.read_byte(address)
-            .unwrap_or_else(|| panic!("Cannot read Memory from {:#04X}", address))
+        self.tick(bus);
+        let value = bus.read_byte(address).unwrap_or_else(|| {
+            bus.fault(ErrorKind::UnmappedRead { address });
+            0xFF
+        });
+        if bus.is_watching() {
+            bus.watch(address, value, false);
+        }
+        value
     }
 
     fn write_byte(&mut self, bus: &mut MemoryBus, address: u16, value: u8) {
-        // self.tick();
-        bus.write_byte(address, value)
-            .unwrap_or_else(|| panic!("Cannot write Memory to {:#04X}", address))
+        self.tick(bus);
+        if bus.is_watching() {
+            bus.watch(address, value, true);
+        }
+        if bus.write_byte(address, value).is_none() {
+            bus.fault(ErrorKind::UnmappedWrite { address });
+        }
     }
 
     fn read_word(&mut self, bus: &mut MemoryBus, address: u16) -> u16 {
@@ -483,22 +687,49 @@ impl CPU {
     }
 
     fn read_word_pc(&mut self, bus: &mut MemoryBus) -> u16 {
-        let byte = self.read_word(bus, self.reg.pc);
-        self.reg.pc = self.reg.pc.wrapping_add(2);
-        byte
+        let low = self.fetch(bus) as u16;
+        let high = self.fetch(bus) as u16;
+        high << 8 | low
     }
 
     fn fetch(&mut self, bus: &mut MemoryBus) -> u8 {
+        self.fetch_as(bus, CDL_OPERAND)
+    }
+
+    // `flag` is how the byte is recorded in the code/data log.
+    fn fetch_as(&mut self, bus: &mut MemoryBus, flag: u8) -> u8 {
+        bus.set_access(flag);
         let byte = self.read_byte(bus, self.reg.pc);
+        bus.set_access(CDL_DATA);
         self.reg.pc = self.reg.pc.wrapping_add(1);
         byte
     }
 
+    // Records a call from `site` that has just pushed `ret` and jumped.
+    fn enter(&mut self, bus: &MemoryBus, kind: FrameKind, site: u16, ret: u16) {
+        self.calls.enter(Frame {
+            kind,
+            site: bus.location(site),
+            target: bus.location(self.reg.pc),
+            return_address: ret,
+            sp: self.reg.sp,
+        });
+    }
+
+    // Pops the return address of a RET or RETI and unwinds the shadow call
+    // stack to match.
+    fn ret(&mut self, bus: &mut MemoryBus) {
+        let at = bus.location(self.reg.pc.wrapping_sub(1));
+        let sp = self.reg.sp;
+        self.reg.pc = self.pop(bus);
+        self.calls.leave(at, sp, self.reg.pc);
+    }
+
     fn push(&mut self, bus: &mut MemoryBus, value: u16) {
-        self.write_byte(bus, self.reg.sp.wrapping_sub(2), (value & 0xFF) as u8);
+        self.tick(bus);
         self.write_byte(bus, self.reg.sp.wrapping_sub(1), (value >> 8) as u8);
+        self.write_byte(bus, self.reg.sp.wrapping_sub(2), (value & 0xFF) as u8);
         self.reg.sp = self.reg.sp.wrapping_sub(2);
-        // self.tick();
     }
     fn pop(&mut self, bus: &mut MemoryBus) -> u16 {
         let word = self.read_word(bus, self.reg.sp);
@@ -573,7 +804,9 @@ impl CPU {
                 Reg8Index::L => self.reg.l = value,
                 Reg8Index::HL => self.write_byte(bus, self.reg.hl(), value as u8),
             },
-            Operand::Value8 => panic!("Writing to byte address is not defined!"),
+            // The decoder never makes an immediate byte a destination; ldh [n], a
+            // is LDOffset and writes through `write_byte` itself.
+            Operand::Value8 => unreachable!("immediate byte used as a destination"),
         }
     }
 
@@ -597,65 +830,296 @@ impl CPU {
         }
     }
 
-    pub fn step(&mut self, bus: &mut MemoryBus) -> u16 {
-        self.cycles = 0;
-        if self.IME && (bus.get_if() & bus.get_ie()) != 0 {
-            self.IME = false;
-            self.halt = false;
-            self.cycles += 8;
-            self.push(bus, self.reg.pc);
-            let int = bus.get_if() & bus.get_ie();
-            for bit in 0..5 {
-                if int & (1 << bit) != 0 {
-                    bus.set_if(bus.get_if() & !(1 << bit));
-                    self.reg.pc = 0x40 + 8 * bit;
-                    break;
-                }
+    fn pending_interrupts(&self, bus: &MemoryBus) -> u8 {
+        bus.get_if() & bus.get_ie() & 0x1F
+    }
+
+    fn halt(&mut self, bus: &MemoryBus) {
+        let pending = self.pending_interrupts(bus) != 0;
+        if !self.IME && pending {
+            self.halt_bug = true;
+        } else {
+            // Right after EI the pending interrupt is taken at once, but it
+            // returns to the HALT rather than past it.
+            self.halt = true;
+            self.halt_bug = pending;
+        }
+    }
+
+    // Follows the DMG flowchart in the Pan Docs. There is no KEY1 register on
+    // DMG, so the CGB speed switch branch never applies.
+    fn stop(&mut self, bus: &mut MemoryBus) {
+        let held = bus.peek(0xFF00) & 0x0F != 0x0F;
+        let pending = self.pending_interrupts(bus) != 0;
+        if held {
+            // STOP turns into HALT, or into a one byte NOP if an interrupt
+            // is already pending. DIV is left alone.
+            if !pending {
+                self.reg.pc = self.reg.pc.wrapping_add(1);
+                self.halt = true;
+            }
+            return;
+        }
+        if !pending {
+            self.reg.pc = self.reg.pc.wrapping_add(1);
+        }
+        // Resetting DIV can still tick TIMA, so this goes through the timer.
+        bus.write_mapped(0xFF04, 0);
+        self.stopped = true;
+    }
+
+    fn dispatch_interrupt(&mut self, bus: &mut MemoryBus) {
+        self.IME = false;
+        self.halt = false;
+        self.cycles += 5;
+        self.tick(bus);
+        self.tick(bus);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.reg.pc = self.reg.pc.wrapping_sub(1);
+        }
+        // The vector is picked after the high byte of PC is pushed. If that
+        // push overwrote IE and nothing is left pending, the dispatch is
+        // cancelled and execution continues at $0000.
+        let pc = self.reg.pc;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write_byte(bus, self.reg.sp, (pc >> 8) as u8);
+        let int = self.pending_interrupts(bus);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write_byte(bus, self.reg.sp, pc as u8);
+        self.reg.pc = 0x0000;
+        for bit in 0..5 {
+            if int & (1 << bit) != 0 {
+                bus.set_if(bus.get_if() & !(1 << bit));
+                self.reg.pc = 0x40 + 8 * bit;
+                break;
             }
+        }
+        self.enter(bus, FrameKind::Interrupt, pc, pc);
+        self.tick(bus);
+    }
+
+    // On error the registers are left as they were after the failing access.
+    pub fn step(&mut self, bus: &mut MemoryBus) -> Result<u16, EmuError> {
+        self.cycles = 0;
+        self.ticked = 0;
+        let start_pc = self.reg.pc;
+        let recording = bus.is_recording();
+        if recording {
+            bus.set_cpu_pc(Some(start_pc));
+        }
+        if self.interrupt_due(bus) {
+            self.dispatch_interrupt(bus);
+        } else if self.stopped {
+            self.cycles += 1;
+        } else if self.locked {
+            // Hung: no fetches and no interrupts, time just passes.
+            self.cycles += 1;
         } else if self.halt {
-            self.cycles += 4;
-            if (bus.get_if() & bus.get_ie()) != 0 {
+            self.cycles += 1;
+            if self.pending_interrupts(bus) != 0 {
                 self.halt = false;
             }
         } else {
+            if self.ei_delay {
+                self.ei_delay = false;
+                self.IME = true;
+            }
+            if self.trace.is_some() {
+                self.emit_trace(bus);
+            }
+            self.history.push(bus.location(self.reg.pc));
             let prev_pc = self.reg.pc;
-            let mut instruction_byte = self.fetch(bus);
-            let mut instruction = Instruction::from_byte(instruction_byte).unwrap_or_else(|| {
-                panic!(
-                    "Unknown instrcution found from {:x}: 0x{:x}",
-                    prev_pc, instruction_byte
-                )
-            });
-            self.cycles += if let Instruction::PREFIX = instruction {
+            let mut instruction_byte = self.fetch_as(bus, CDL_CODE);
+            if self.halt_bug {
+                self.halt_bug = false;
+                self.reg.pc = prev_pc;
+            }
+            let mut instruction = Instruction::from_byte(instruction_byte);
+            self.cycles += if let Some(Instruction::PREFIX) = instruction {
                 instruction_byte = self.fetch(bus);
-                instruction =
-                    Instruction::from_byte_prefixed(instruction_byte).unwrap_or_else(|| {
-                        panic!(
-                            "Unknown instrcution found from {:x}: 0xCB{:x}",
-                            prev_pc, instruction_byte
-                        )
-                    });
+                instruction = Instruction::from_byte_prefixed(instruction_byte);
                 inst_cb_time[instruction_byte as usize] as u16
             } else {
                 inst_time[instruction_byte as usize] as u16
             };
-            // println!(
-            //     "pc = {:04X}, sp = {:04X}, af = {:04X}, bc = {:04X}, de = {:04X}, hl = {:04X}, inst = {:02X} : {:?}",
-            //     prev_pc,
-            //     self.reg.sp,
-            //     self.reg.af(),
-            //     self.reg.bc(),
-            //     self.reg.de(),
-            //     self.reg.hl(),
-            //     instruction_byte,
-            //     instruction
-            // );
-            self.execute(bus, instruction);
+            match instruction {
+                Some(instruction) => {
+                    self.execute(bus, instruction);
+                }
+                None => {
+                    // PC stays on the opcode so the host can see what hung.
+                    self.locked = true;
+                    self.reg.pc = prev_pc;
+                    self.cycles = 1;
+                }
+            }
+        }
+        if recording {
+            bus.set_cpu_pc(None);
+        }
+        let cycles = self.cycles.max(self.ticked);
+        bus.scheduler().advance(((cycles - self.ticked) * 4) as u64);
+        match bus.take_fault() {
+            Some(kind) => Err(self.error(kind, start_pc)),
+            None => Ok(cycles * 4),
         }
-        self.cycles * 4
+    }
+}
+
+impl SaveState for CPU {
+    fn save(&self, w: &mut StateWriter) {
+        self.reg.save(w);
+        w.bool(self.IME);
+        w.bool(self.halt);
+        w.bool(self.locked);
+        w.bool(self.ei_delay);
+        w.bool(self.halt_bug);
+        w.bool(self.stopped);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.reg.load(r)?;
+        self.IME = r.bool()?;
+        self.halt = r.bool()?;
+        self.locked = r.bool()?;
+        self.ei_delay = r.bool()?;
+        self.halt_bug = r.bool()?;
+        self.stopped = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::{WatchKind, Watchpoint};
+    use crate::device::{Device, IOHandler};
+    use crate::mmu::{MemoryRead, MemoryWrite};
+    use crate::recorder::{AccessRecorder, RecorderConfig};
+    use alloc::{vec, vec::Vec};
+
+    // A bare bus is plain RAM, so the program can be written anywhere.
+    fn setup(program: &[u8], ie: u8, int: u8) -> (CPU, MemoryBus) {
+        let mut bus = MemoryBus::new();
+        for (idx, byte) in program.iter().enumerate() {
+            bus.write_byte(0x0100 + idx as u16, *byte);
+        }
+        bus.write_byte(0xFFFF, ie);
+        bus.set_if(int);
+        (CPU::new(), bus)
+    }
+
+    #[test]
+    fn ei_takes_effect_after_next_instruction() {
+        // ei; inc b; inc b
+        let (mut cpu, mut bus) = setup(&[0xFB, 0x04, 0x04], 0x01, 0x01);
+        assert!(!cpu.IME);
+        cpu.step(&mut bus).unwrap();
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.reg.b, 1);
+        assert_eq!(cpu.step(&mut bus), Ok(20));
+        assert_eq!(cpu.reg.pc, 0x0040);
+        assert_eq!(cpu.reg.b, 1);
+        assert_eq!(bus.get_if(), 0x00);
+        assert_eq!(cpu.pop(&mut bus), 0x0102);
+    }
+
+    #[test]
+    fn halt_bug_repeats_next_byte() {
+        // halt; inc b; inc c
+        let (mut cpu, mut bus) = setup(&[0x76, 0x04, 0x0C], 0x04, 0x04);
+        cpu.step(&mut bus).unwrap();
+        assert!(!cpu.halt);
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.reg.pc, 0x0101);
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.reg.pc, 0x0102);
+        assert_eq!(cpu.reg.b, 2);
+    }
+
+    #[test]
+    fn ei_halt_returns_to_halt() {
+        // ei; halt
+        let (mut cpu, mut bus) = setup(&[0xFB, 0x76], 0x01, 0x01);
+        cpu.step(&mut bus).unwrap();
+        cpu.step(&mut bus).unwrap();
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.reg.pc, 0x0040);
+        assert_eq!(cpu.pop(&mut bus), 0x0101);
     }
 
-    // fn tick(&mut self) {
-    //     self.cycles += self.cycles.wrapping_add(4);
-    // }
+    #[test]
+    fn ie_overwritten_by_push_cancels_dispatch() {
+        // ld sp, $0000; ei; nop
+        let (mut cpu, mut bus) = setup(&[0x31, 0x00, 0x00, 0xFB, 0x00], 0x02, 0x02);
+        for _ in 0..3 {
+            cpu.step(&mut bus).unwrap();
+        }
+        // The high byte of PC ($01) lands in IE, masking the timer interrupt.
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(bus.get_ie(), 0x01);
+        assert_eq!(cpu.reg.pc, 0x0000);
+        assert_eq!(bus.get_if(), 0x02);
+    }
+
+    // Records the time of each read.
+    struct Probe(Vec<u64>);
+
+    impl IOHandler for Probe {
+        fn read(&mut self, mmu: &MemoryBus, _address: u16) -> MemoryRead {
+            self.0.push(mmu.scheduler().now());
+            MemoryRead::Value(0)
+        }
+        fn write(&mut self, _mmu: &MemoryBus, _address: u16, _value: u8) -> MemoryWrite {
+            MemoryWrite::PassThrough
+        }
+    }
+
+    #[test]
+    fn accurate_mode_times_each_access() {
+        // ld a, [$FF80]; ldh a, [$80]; xor a; jr z, +0
+        let program = [0xFA, 0x80, 0xFF, 0xF0, 0x80, 0xAF, 0x28, 0x00];
+        for (accurate, reads) in [(false, [0, 16]), (true, [16, 28])] {
+            let (mut cpu, mut bus) = setup(&program, 0, 0);
+            let probe = Device::new(Probe(Vec::new()));
+            bus.add_handler((0xFF80, 0xFF80), probe.handler());
+            cpu.set_accurate(accurate);
+
+            let cycles: Vec<u16> = (0..4).map(|_| cpu.step(&mut bus).unwrap()).collect();
+            assert_eq!(cycles, [16, 12, 4, 12]);
+            assert_eq!(bus.scheduler().now(), 44);
+            assert_eq!(probe.borrow().0, reads);
+        }
+    }
+
+    #[test]
+    fn accurate_mode_keeps_stop_and_halt_timing() {
+        // stop; halt
+        for accurate in [false, true] {
+            let (mut cpu, mut bus) = setup(&[0x10, 0x00, 0x76], 0, 0);
+            bus.write_byte(0xFF00, 0xFF);
+            bus.watchpoints().push(Watchpoint {
+                start: 0xFF00,
+                end: 0xFF07,
+                kind: WatchKind::Access,
+                value: None,
+            });
+            bus.set_recorder(Some(AccessRecorder::new(RecorderConfig {
+                ranges: vec![(0xFF00, 0xFF07)],
+                reads: true,
+                ..RecorderConfig::default()
+            })));
+            cpu.set_accurate(accurate);
+
+            assert_eq!(cpu.step(&mut bus), Ok(4));
+            assert!(cpu.is_stopped());
+            cpu.wake();
+            assert_eq!(cpu.step(&mut bus), Ok(4));
+            assert!(cpu.halt);
+            assert_eq!(bus.scheduler().now(), 8);
+            // STOP's P1 check and DIV reset are not CPU accesses.
+            assert_eq!(bus.take_watch_hit(), None);
+            assert!(bus.recorder().unwrap().take_accesses().is_empty());
+        }
+    }
 }
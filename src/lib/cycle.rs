@@ -1,56 +1,132 @@
 use crate::{
     device::IOHandler,
     mmu::{MemoryBus, MemoryRead, MemoryWrite},
+    scheduler::{Event, Scheduler},
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
 
+// The timer is driven by one bit of the 16-bit divider, picked by TAC.
+// TIMA counts on the falling edge of that bit ANDed with the enable bit.
 pub struct Clock {
-    cycles: u16,
+    div: u16,
     counter: u8,
     TMA: u8,
     TAC: u8,
-    inc: u8,
+    // TIMA overflowed during the last M-cycle and reads 0 until the reload.
+    overflow: bool,
+    // TMA was copied into TIMA during the last M-cycle.
+    reloading: bool,
+    last_sync: u64,
+    interrupt: bool,
 }
 
 impl Clock {
     pub fn new() -> Clock {
         Clock {
-            cycles: 44288,
+            div: 44288,
             counter: 0,
             TMA: 0,
             TAC: 0,
-            inc: 0,
+            overflow: false,
+            reloading: false,
+            last_sync: 0,
+            interrupt: false,
         }
     }
 
-    pub fn step(&mut self, mmu: &mut MemoryBus, elapsed_cycle: u16) {
-        self.cycles = self.cycles.wrapping_add(elapsed_cycle);
-        if self.TAC & 0x04 == 0x04 {
-            let divider = match self.TAC & 0x03 {
-                0 => 1024,
-                1 => 16,
-                2 => 64,
-                3 => 256,
-                _ => unreachable!(),
-            } as u16;
-            // println!("{}", divider);
-            let new_cycles = (elapsed_cycle / divider) as u8;
-            if 0xFF - self.counter < new_cycles {
-                let rest = new_cycles - (0xFF - self.counter);
-                self.counter = self.TMA + rest;
-                mmu.set_if(mmu.get_if() | 0x04);
-                // println!("{}", mmu.get_if());
-            } else {
-                self.counter += new_cycles;
-            }
-            // println!("{} {} {}", new_cycles, self.counter, self.TMA);
+    fn signal(&self) -> bool {
+        let bit = match self.TAC & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.TAC & 0x04 != 0 && self.div & (1 << bit) != 0
+    }
+
+    fn increment(&mut self) {
+        self.counter = self.counter.wrapping_add(1);
+        self.overflow = self.counter == 0;
+    }
+
+    // Runs `update` and counts a falling edge of the timer signal, if any.
+    fn edge(&mut self, update: impl FnOnce(&mut Clock)) {
+        let before = self.signal();
+        update(self);
+        if before && !self.signal() {
+            self.increment();
         }
     }
+
+    fn tick(&mut self) {
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.counter = self.TMA;
+            self.interrupt = true;
+            self.reloading = true;
+        }
+        self.edge(|clock| clock.div = clock.div.wrapping_add(4));
+    }
+
+    // Catches the timer up to the scheduler's current time. Registers are
+    // synced on every access, so reads see the value at the exact cycle.
+    pub fn sync(&mut self, scheduler: &Scheduler) {
+        let now = scheduler.now();
+        let elapsed = now - self.last_sync;
+        self.last_sync = now;
+        if self.TAC & 0x04 == 0 && !self.overflow && !self.reloading {
+            self.div = self.div.wrapping_add(elapsed as u16);
+            return;
+        }
+        for _ in 0..elapsed / 4 {
+            self.tick();
+        }
+    }
+
+    // Forgets any time that passed before `now`, e.g. after loading a state.
+    pub fn resync(&mut self, scheduler: &Scheduler) {
+        self.last_sync = scheduler.now();
+        self.schedule(scheduler);
+    }
+
+    // Schedules the timer event for when the interrupt is next raised, from
+    // the state as of the last sync. It must never be late: the System only
+    // syncs the timer when the event fires, and an early event reschedules.
+    pub fn schedule(&self, scheduler: &Scheduler) {
+        let ticks = if self.interrupt {
+            0
+        } else if self.overflow {
+            1
+        } else if self.TAC & 0x04 != 0 {
+            // TIMA counts once per period, on the tick that clears the bit.
+            let period = match self.TAC & 0x03 {
+                0 => 256,
+                1 => 4,
+                2 => 16,
+                _ => 64,
+            };
+            let to_edge = period - (self.div / 4) as u64 % period;
+            // Overflow on the last count; the interrupt comes a tick later.
+            to_edge + (255 - self.counter as u64) * period + 1
+        } else {
+            scheduler.cancel(Event::Timer);
+            return;
+        };
+        scheduler.schedule(Event::Timer, self.last_sync + ticks * 4);
+    }
+
+    // Returns true once after TIMA has been reloaded from an overflow.
+    pub fn take_interrupt(&mut self) -> bool {
+        core::mem::replace(&mut self.interrupt, false)
+    }
 }
 
 impl IOHandler for Clock {
     fn read(&mut self, mmu: &MemoryBus, address: u16) -> MemoryRead {
+        self.sync(mmu.scheduler());
         match address {
-            0xFF04 => MemoryRead::Value((self.cycles >> 8) as u8),
+            0xFF04 => MemoryRead::Value((self.div >> 8) as u8),
             0xFF05 => MemoryRead::Value(self.counter),
             0xFF06 => MemoryRead::Value(self.TMA),
             0xFF07 => MemoryRead::Value(self.TAC),
@@ -58,13 +134,161 @@ impl IOHandler for Clock {
         }
     }
     fn write(&mut self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        self.sync(mmu.scheduler());
+        match address {
+            // Resetting the divider or switching TAC can drop the signal
+            // and so bump TIMA.
+            0xFF04 => self.edge(|clock| clock.div = 0),
+            0xFF07 => self.edge(|clock| clock.TAC = value),
+            // A write in the cycle after an overflow cancels the reload; one
+            // during the reload itself is lost.
+            0xFF05 if !self.reloading => {
+                self.counter = value;
+                self.overflow = false;
+            }
+            0xFF06 => {
+                self.TMA = value;
+                if self.reloading {
+                    self.counter = value;
+                }
+            }
+            _ => {}
+        }
+        self.schedule(mmu.scheduler());
+        MemoryWrite::PassThrough
+    }
+    // Sets the registers as they are, without edges or reload handling.
+    fn poke(&mut self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        self.sync(mmu.scheduler());
         match address {
-            0xFF04 => self.cycles &= 0x00FF,
+            0xFF04 => self.div = (value as u16) << 8,
             0xFF05 => self.counter = value,
             0xFF06 => self.TMA = value,
             0xFF07 => self.TAC = value,
             _ => {}
         }
+        self.schedule(mmu.scheduler());
         MemoryWrite::PassThrough
     }
 }
+
+impl SaveState for Clock {
+    fn save(&self, w: &mut StateWriter) {
+        w.u16(self.div);
+        w.bytes(&[self.counter, self.TMA, self.TAC]);
+        w.bool(self.overflow);
+        w.bool(self.reloading);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.div = r.u16()?;
+        self.counter = r.u8()?;
+        self.TMA = r.u8()?;
+        self.TAC = r.u8()?;
+        self.overflow = r.bool()?;
+        self.reloading = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(tac: u8) -> (Clock, MemoryBus) {
+        let mut clock = Clock::new();
+        let bus = MemoryBus::new();
+        clock.div = 0;
+        clock.write(&bus, 0xFF07, tac);
+        (clock, bus)
+    }
+
+    fn read(clock: &mut Clock, bus: &MemoryBus, address: u16) -> u8 {
+        match clock.read(bus, address) {
+            MemoryRead::Value(value) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn counts_falling_edges_across_syncs() {
+        // 16 cycles per tick, synced at odd instruction lengths.
+        let (mut clock, bus) = clock(0x05);
+        for _ in 0..20 {
+            bus.scheduler().advance(12);
+            clock.sync(bus.scheduler());
+        }
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 15);
+    }
+
+    #[test]
+    fn overflow_reloads_one_cycle_late() {
+        let (mut clock, bus) = clock(0x05);
+        clock.write(&bus, 0xFF06, 0x80);
+        clock.write(&bus, 0xFF05, 0xFF);
+        bus.scheduler().advance(16);
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 0x00);
+        assert!(!clock.take_interrupt());
+        bus.scheduler().advance(4);
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 0x80);
+        assert!(clock.take_interrupt());
+
+        // Writing TIMA right after the overflow cancels the reload.
+        clock.write(&bus, 0xFF05, 0xFF);
+        bus.scheduler().advance(16);
+        clock.write(&bus, 0xFF05, 0x42);
+        bus.scheduler().advance(4);
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 0x42);
+        assert!(!clock.take_interrupt());
+    }
+
+    #[test]
+    fn write_during_reload_keeps_tma() {
+        let (mut clock, bus) = clock(0x05);
+        clock.write(&bus, 0xFF06, 0x80);
+        clock.write(&bus, 0xFF05, 0xFF);
+        bus.scheduler().advance(20);
+        clock.write(&bus, 0xFF05, 0x42);
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 0x80);
+        clock.write(&bus, 0xFF06, 0x90);
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 0x90);
+    }
+
+    #[test]
+    fn event_is_due_when_interrupt_is_raised() {
+        for (tac, tima, div) in [
+            (0x04, 0xFE, 0),
+            (0x05, 0xF0, 44),
+            (0x06, 0xFF, 60),
+            (0x07, 0x80, 1000),
+        ] {
+            let (mut clock, bus) = clock(tac);
+            clock.div = div;
+            clock.counter = tima;
+            clock.schedule(bus.scheduler());
+            let due = bus.scheduler().deadline(Event::Timer).unwrap();
+            // Ticking one M-cycle at a time, as the System used to.
+            while !clock.take_interrupt() {
+                assert!(bus.scheduler().now() < due);
+                bus.scheduler().advance(4);
+                clock.sync(bus.scheduler());
+            }
+            assert_eq!(bus.scheduler().now(), due);
+        }
+        let (clock, bus) = clock(0x00);
+        clock.schedule(bus.scheduler());
+        assert_eq!(bus.scheduler().deadline(Event::Timer), None);
+    }
+
+    #[test]
+    fn div_reset_bumps_tima() {
+        let (mut clock, bus) = clock(0x05);
+        bus.scheduler().advance(8);
+        clock.write(&bus, 0xFF04, 0x12);
+        assert_eq!(read(&mut clock, &bus, 0xFF04), 0x00);
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 0x01);
+        // With bit 3 low, resetting DIV leaves TIMA alone.
+        bus.scheduler().advance(4);
+        clock.write(&bus, 0xFF04, 0x12);
+        assert_eq!(read(&mut clock, &bus, 0xFF05), 0x01);
+    }
+}
@@ -0,0 +1,408 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::fmt;
+
+use crate::cpu::CpuState;
+use crate::inst::Instruction;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    // Only break while this ROM bank is mapped at `address`. Ignored for
+    // addresses outside of ROM.
+    pub bank: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    // Inclusive range of watched addresses.
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    // Only trigger when this value is read or written.
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, value: u8, write: bool) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        kind && (self.start..=self.end).contains(&address)
+            && self.value.is_none_or(|expected| expected == value)
+    }
+}
+
+// A CPU access that matched a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+// The CPU state just before an instruction runs, and the four bytes at PC.
+// Displays as a line of a gameboy-doctor log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub cpu: CpuState,
+    pub pcmem: [u8; 4],
+    // ROM bank mapped at $4000-$7FFF, for looking up symbols.
+    pub bank: u16,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = &self.cpu;
+        write!(
+            f,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            s.a, s.f, s.b, s.c, s.d, s.e, s.h, s.l, s.sp, s.pc,
+            self.pcmem[0], self.pcmem[1], self.pcmem[2], self.pcmem[3]
+        )
+    }
+}
+
+// An address and the ROM bank mapped there when it was used. The bank is 0
+// outside of ROM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub bank: u16,
+    pub address: u16,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}:{:04X}", self.bank, self.address)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+// One entry of the shadow call stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // The call instruction, or the instruction an interrupt came before.
+    pub site: Location,
+    pub target: Location,
+    pub return_address: u16,
+    // Where the return address was pushed.
+    pub sp: u16,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            FrameKind::Call => "call",
+            FrameKind::Rst => "rst",
+            FrameKind::Interrupt => "interrupt",
+        };
+        write!(f, "{} {} from {}", kind, self.target, self.site)
+    }
+}
+
+// Something the shadow call stack could not match up, usually because the
+// game moved SP or used the stack for something other than calls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackWarning {
+    // A return with no call left to return from.
+    UnmatchedReturn {
+        at: Location,
+        to: u16,
+    },
+    // A return from the right depth, but not to after the call.
+    WrongReturn {
+        at: Location,
+        to: u16,
+        expected: u16,
+    },
+    // A return above these frames, which were never returned from.
+    Abandoned {
+        at: Location,
+        frames: usize,
+    },
+    // Nested too deep; the outermost frame was dropped.
+    Overflow {
+        at: Location,
+    },
+}
+
+impl fmt::Display for StackWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StackWarning::UnmatchedReturn { at, to } => {
+                write!(f, "return at {} to {:04X} without a call", at, to)
+            }
+            StackWarning::WrongReturn { at, to, expected } => write!(
+                f,
+                "return at {} to {:04X} instead of {:04X}",
+                at, to, expected
+            ),
+            StackWarning::Abandoned { at, frames } => {
+                write!(f, "return at {} skipped {} frame(s)", at, frames)
+            }
+            StackWarning::Overflow { at } => write!(f, "call stack overflow at {}", at),
+        }
+    }
+}
+
+const MAX_FRAMES: usize = 256;
+const MAX_WARNINGS: usize = 16;
+
+pub struct CallStack {
+    frames: Vec<Frame>,
+    // The most recent warnings, oldest first.
+    warnings: Vec<StackWarning>,
+    // Set when tracking starts part way through, e.g. after loading a state.
+    // Returns from at or above it belong to calls made before then.
+    floor: Option<u16>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+            warnings: Vec::new(),
+            floor: None,
+        }
+    }
+
+    // Innermost last.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn warnings(&self) -> &[StackWarning] {
+        &self.warnings
+    }
+
+    pub fn clear_warnings(&mut self) {
+        self.warnings.clear();
+    }
+
+    // Starts over with the stack at `sp`.
+    pub fn reset(&mut self, sp: u16) {
+        self.frames.clear();
+        self.warnings.clear();
+        self.floor = Some(sp);
+    }
+
+    fn warn(&mut self, warning: StackWarning) {
+        if self.warnings.len() == MAX_WARNINGS {
+            self.warnings.remove(0);
+        }
+        self.warnings.push(warning);
+    }
+
+    pub fn enter(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.remove(0);
+            self.warn(StackWarning::Overflow { at: frame.site });
+        }
+        self.frames.push(frame);
+    }
+
+    // A return at `at` popped `to` from `sp`.
+    pub fn leave(&mut self, at: Location, sp: u16, to: u16) {
+        let mut frames = 0;
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+            frames += 1;
+        }
+        if frames > 0 {
+            self.warn(StackWarning::Abandoned { at, frames });
+        }
+        match self.frames.last() {
+            Some(frame) if frame.sp == sp => {
+                let expected = frame.return_address;
+                self.frames.pop();
+                if expected != to {
+                    self.warn(StackWarning::WrongReturn { at, to, expected });
+                }
+            }
+            _ => match self.floor {
+                Some(floor) if sp >= floor => self.floor = Some(sp.wrapping_add(2)),
+                _ => self.warn(StackWarning::UnmatchedReturn { at, to }),
+            },
+        }
+    }
+}
+
+// The last executed instructions, oldest first.
+pub struct History {
+    entries: VecDeque<Location>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, location: Location) {
+        if self.entries.len() == self.capacity {
+            if self.capacity == 0 {
+                return;
+            }
+            self.entries.pop_front();
+        }
+        self.entries.push_back(location);
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+        self.capacity = capacity;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Location> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+// A pending step-over, step-out or run-until-return.
+#[derive(Clone, Copy)]
+enum StepTarget {
+    // Back at `pc` with the stack no deeper than `sp`.
+    Over { pc: u16, sp: u16 },
+    // A return popped the frame that was innermost on the shadow call stack,
+    // which then held `depth` frames. Without a frame to go by, any return
+    // that leaves the stack above `sp`.
+    Out { sp: u16, depth: usize },
+    // About to return with the stack no deeper than `sp`.
+    Return { sp: u16 },
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    target: Option<StepTarget>,
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(
+        Instruction::from_byte(opcode),
+        Some(Instruction::RET(_)) | Some(Instruction::RETI)
+    )
+}
+
+fn is_call(opcode: u8) -> bool {
+    matches!(
+        Instruction::from_byte(opcode),
+        Some(Instruction::CALL(_)) | Some(Instruction::RST(_))
+    )
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            target: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.breakpoints.is_empty() || self.target.is_some()
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Returns false if the instruction at `pc` is not a call, in which case
+    // stepping over it is the same as stepping into it.
+    pub fn step_over(&mut self, pc: u16, sp: u16, opcode: u8, length: u8) -> bool {
+        if !is_call(opcode) {
+            return false;
+        }
+        self.target = Some(StepTarget::Over {
+            pc: pc.wrapping_add(length as u16),
+            sp,
+        });
+        true
+    }
+
+    // `depth` is the number of frames on the shadow call stack.
+    pub fn step_out(&mut self, sp: u16, depth: usize) {
+        self.target = Some(StepTarget::Out { sp, depth });
+    }
+
+    pub fn run_until_return(&mut self, sp: u16) {
+        self.target = Some(StepTarget::Return { sp });
+    }
+
+    pub fn cancel_step(&mut self) {
+        self.target = None;
+    }
+
+    // Checked before the CPU executes the instruction at `pc`. On the first
+    // step of a run the current position never counts as reached.
+    pub fn before(&mut self, pc: u16, sp: u16, opcode: u8, first: bool) -> bool {
+        let reached = match self.target {
+            Some(StepTarget::Over { pc: at, sp: depth }) => pc == at && sp >= depth,
+            Some(StepTarget::Return { sp: depth }) => !first && is_return(opcode) && sp >= depth,
+            _ => false,
+        };
+        if reached {
+            self.target = None;
+        }
+        reached
+    }
+
+    // `bank` is the ROM bank mapped at `pc`, or None outside of ROM.
+    pub fn breaks_at(&self, pc: u16, bank: Option<u16>) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|b| b.address == pc && (b.bank.is_none() || bank.is_none() || b.bank == bank))
+    }
+
+    // Checked after the instruction `opcode` ran, with the new SP and call
+    // stack depth.
+    pub fn after(&mut self, opcode: u8, sp: u16, depth: usize) -> bool {
+        let reached = match self.target {
+            Some(StepTarget::Out {
+                sp: start,
+                depth: 0,
+            }) => is_return(opcode) && sp > start,
+            Some(StepTarget::Out { depth: frames, .. }) => is_return(opcode) && depth < frames,
+            _ => false,
+        };
+        if reached {
+            self.target = None;
+        }
+        reached
+    }
+}
@@ -1,6 +1,7 @@
 use alloc::rc::Rc;
 use core::cell::{Ref, RefCell, RefMut};
 
+use crate::error::ErrorKind;
 use crate::mmu::{MemoryBus, MemoryHandler, MemoryRead, MemoryWrite};
 
 pub struct Device<T>(Rc<RefCell<T>>, bool);
@@ -29,9 +30,26 @@ impl<T> Device<T> {
     }
 }
 
+// Another handle to the same device.
+impl<T> Clone for Device<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), self.1)
+    }
+}
+
 pub trait IOHandler {
     fn read(&mut self, mmu: &MemoryBus, address: u16) -> MemoryRead;
     fn write(&mut self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite;
+
+    // Host access from outside of emulation. Neither may have side effects
+    // such as faults, interrupts or bank switches; devices whose reads or
+    // writes do have them override these.
+    fn peek(&mut self, mmu: &MemoryBus, address: u16) -> MemoryRead {
+        self.read(mmu, address)
+    }
+    fn poke(&mut self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        self.write(mmu, address, value)
+    }
 }
 
 impl<T: IOHandler> Device<T> {
@@ -44,27 +62,37 @@ impl<T: IOHandler> MemoryHandler for DevHandler<T> {
     fn read(&self, mmu: &MemoryBus, address: u16) -> MemoryRead {
         match self.0.try_borrow_mut() {
             Ok(mut inner) => inner.read(mmu, address),
-            Err(e) => {
-                if self.1 {
-                    MemoryRead::PassThrough
-                } else {
-                    panic!("Recursive read from {:04X} : {}", address, e);
+            Err(_) => {
+                if !self.1 {
+                    mmu.fault(ErrorKind::RecursiveAccess { address });
                 }
+                MemoryRead::PassThrough
             }
         }
     }
     fn write(&self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
         match self.0.try_borrow_mut() {
             Ok(mut inner) => inner.write(mmu, address, value),
-            Err(e) => {
-                if self.1 {
-                    MemoryWrite::PassThrough
-                } else {
-                    panic!("Recursive write to {:04X} : {}", address, e);
+            Err(_) => {
+                if !self.1 {
+                    mmu.fault(ErrorKind::RecursiveAccess { address });
                 }
+                MemoryWrite::Block
             }
         }
     }
+    fn peek(&self, mmu: &MemoryBus, address: u16) -> MemoryRead {
+        match self.0.try_borrow_mut() {
+            Ok(mut inner) => inner.peek(mmu, address),
+            Err(_) => MemoryRead::PassThrough,
+        }
+    }
+    fn poke(&self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        match self.0.try_borrow_mut() {
+            Ok(mut inner) => inner.poke(mmu, address, value),
+            Err(_) => MemoryWrite::Block,
+        }
+    }
 }
 
 #[cfg(test)]
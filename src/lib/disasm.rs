@@ -0,0 +1,866 @@
+use alloc::{format, string::String};
+use core::fmt;
+
+use crate::inst::{
+    inst_cb_time, inst_time, Condition, Instruction, Operand, Reg16Index, Reg8Index,
+};
+use crate::symbols::Symbols;
+
+// One decoded instruction, in RGBDS syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Disassembly {
+    pub address: u16,
+    pub text: String,
+    pub length: u8,
+    // In T-cycles. For a conditional branch this is the time when the branch
+    // is not taken, and `taken_cycles` the time when it is.
+    pub cycles: u8,
+    pub taken_cycles: Option<u8>,
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// Decodes the instruction at the start of `bytes`, which is located at
+// `address`. Returns None if `bytes` ends before the instruction does.
+pub fn disassemble(bytes: &[u8], address: u16) -> Option<Disassembly> {
+    decode(bytes, address, None)
+}
+
+// Like `disassemble`, but addresses that have a label are shown by name.
+// `bank` is the ROM bank mapped at $4000-$7FFF.
+pub fn disassemble_with_symbols(
+    bytes: &[u8],
+    address: u16,
+    bank: u16,
+    symbols: &Symbols,
+) -> Option<Disassembly> {
+    decode(bytes, address, Some((symbols, bank)))
+}
+
+fn decode(bytes: &[u8], address: u16, labels: Option<(&Symbols, u16)>) -> Option<Disassembly> {
+    let opcode = *bytes.first()?;
+    let mut r = Reader {
+        bytes,
+        len: 1,
+        address,
+        labels,
+    };
+    let (text, cycles) = match opcode {
+        0xCB => {
+            let opcode = r.u8()?;
+            let inst = Instruction::from_byte_prefixed(opcode).unwrap();
+            (format_inst(&inst, &mut r)?, inst_cb_time[opcode as usize])
+        }
+        _ => match Instruction::from_byte(opcode) {
+            Some(inst) => (format_inst(&inst, &mut r)?, inst_time[opcode as usize]),
+            None => (format!("db ${:02X}", opcode), 0),
+        },
+    };
+    let extra = match Instruction::from_byte(opcode) {
+        Some(Instruction::JR(cond)) | Some(Instruction::JP(cond)) => branch_extra(cond, 1),
+        Some(Instruction::CALL(cond)) | Some(Instruction::RET(cond)) => branch_extra(cond, 3),
+        _ => None,
+    };
+    Some(Disassembly {
+        address,
+        text,
+        length: r.len as u8,
+        cycles: cycles * 4,
+        taken_cycles: extra.map(|extra| (cycles + extra) * 4),
+    })
+}
+
+fn branch_extra(cond: Condition, extra: u8) -> Option<u8> {
+    match cond {
+        Condition::ALWAYS => None,
+        _ => Some(extra),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    len: usize,
+    address: u16,
+    labels: Option<(&'a Symbols, u16)>,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let value = *self.bytes.get(self.len)?;
+        self.len += 1;
+        Some(value)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let low = self.u8()? as u16;
+        let high = self.u8()? as u16;
+        Some(high << 8 | low)
+    }
+
+    fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.len as u16)
+    }
+
+    fn target(&self, address: u16) -> String {
+        let label = self
+            .labels
+            .and_then(|(symbols, bank)| symbols.label(bank, address));
+        match label {
+            Some(label) => label.into(),
+            None => format!("${:04X}", address),
+        }
+    }
+}
+
+fn format_inst(inst: &Instruction, r: &mut Reader) -> Option<String> {
+    let text = match *inst {
+        Instruction::NOP => "nop".into(),
+        Instruction::HALT => "halt".into(),
+        Instruction::STOP => {
+            // The byte after STOP is skipped, and RGBDS emits it as $00.
+            r.u8()?;
+            "stop".into()
+        }
+        Instruction::DI => "di".into(),
+        Instruction::EI => "ei".into(),
+
+        Instruction::JR(cond) => {
+            let offset = r.u8()? as i8;
+            let target = r.next_address().wrapping_add(offset as u16);
+            branch("jr", cond, r.target(target))
+        }
+        Instruction::JP(cond) => {
+            let target = r.u16()?;
+            branch("jp", cond, r.target(target))
+        }
+        Instruction::JPHL => "jp hl".into(),
+        Instruction::RET(Condition::ALWAYS) => "ret".into(),
+        Instruction::RET(cond) => format!("ret {}", condition(cond)),
+        Instruction::RETI => "reti".into(),
+        Instruction::CALL(cond) => {
+            let target = r.u16()?;
+            branch("call", cond, r.target(target))
+        }
+
+        Instruction::PUSH(op) => format!("push {}", operand16(op, r)?),
+        Instruction::POP(op) => format!("pop {}", operand16(op, r)?),
+
+        Instruction::LD(dst, src) => {
+            format!("ld {}, {}", operand8(dst, r)?, operand8(src, r)?)
+        }
+        Instruction::LD16(Operand::Value16, src) => {
+            let address = r.u16()?;
+            format!("ld [{}], {}", r.target(address), operand16(src, r)?)
+        }
+        Instruction::LD16(dst, src) => {
+            format!("ld {}, {}", operand16(dst, r)?, operand16(src, r)?)
+        }
+        Instruction::LDOffset(Operand::Register16(_), _) => {
+            format!("ld hl, sp{}", signed(r.u8()? as i8))
+        }
+        Instruction::LDOffset(dst, src) => {
+            format!("ldh {}, {}", high_operand(dst, r)?, high_operand(src, r)?)
+        }
+
+        Instruction::INC(op) => format!("inc {}", operand16(op, r)?),
+        Instruction::DEC(op) => format!("dec {}", operand16(op, r)?),
+
+        Instruction::ADD(op) => format!("add a, {}", operand8(op, r)?),
+        Instruction::ADDHL(op) => format!("add hl, {}", operand16(op, r)?),
+        Instruction::ADDSP => format!("add sp, {}", signed(r.u8()? as i8)),
+        Instruction::ADC(op) => format!("adc a, {}", operand8(op, r)?),
+        Instruction::SUB(op) => format!("sub a, {}", operand8(op, r)?),
+        Instruction::SBC(op) => format!("sbc a, {}", operand8(op, r)?),
+        Instruction::AND(op) => format!("and a, {}", operand8(op, r)?),
+        Instruction::OR(op) => format!("or a, {}", operand8(op, r)?),
+        Instruction::XOR(op) => format!("xor a, {}", operand8(op, r)?),
+        Instruction::CP(op) => format!("cp a, {}", operand8(op, r)?),
+        Instruction::CPL => "cpl".into(),
+
+        Instruction::CCF => "ccf".into(),
+        Instruction::SCF => "scf".into(),
+
+        Instruction::RRA => "rra".into(),
+        Instruction::RLA => "rla".into(),
+        Instruction::RRCA => "rrca".into(),
+        Instruction::RLCA => "rlca".into(),
+
+        Instruction::RR(op) => format!("rr {}", operand8(op, r)?),
+        Instruction::RL(op) => format!("rl {}", operand8(op, r)?),
+        Instruction::RRC(op) => format!("rrc {}", operand8(op, r)?),
+        Instruction::RLC(op) => format!("rlc {}", operand8(op, r)?),
+
+        Instruction::DAA => "daa".into(),
+
+        Instruction::BIT(bit, op) => format!("bit {}, {}", bit, operand8(op, r)?),
+        Instruction::SET(bit, op) => format!("set {}, {}", bit, operand8(op, r)?),
+        Instruction::RES(bit, op) => format!("res {}, {}", bit, operand8(op, r)?),
+
+        Instruction::SLA(op) => format!("sla {}", operand8(op, r)?),
+        Instruction::SRA(op) => format!("sra {}", operand8(op, r)?),
+        Instruction::SWAP(op) => format!("swap {}", operand8(op, r)?),
+        Instruction::SRL(op) => format!("srl {}", operand8(op, r)?),
+
+        Instruction::RST(vector) => format!("rst ${:02X}", vector),
+        Instruction::PREFIX => unreachable!(),
+    };
+    Some(text)
+}
+
+fn branch(mnemonic: &str, cond: Condition, target: String) -> String {
+    match cond {
+        Condition::ALWAYS => format!("{} {}", mnemonic, target),
+        _ => format!("{} {}, {}", mnemonic, condition(cond), target),
+    }
+}
+
+fn condition(cond: Condition) -> &'static str {
+    match cond {
+        Condition::NZ => "nz",
+        Condition::Z => "z",
+        Condition::NC => "nc",
+        Condition::C => "c",
+        Condition::ALWAYS => "",
+    }
+}
+
+fn signed(value: i8) -> String {
+    match value {
+        0.. => format!("+${:02X}", value),
+        _ => format!("-${:02X}", value.unsigned_abs()),
+    }
+}
+
+fn reg8(reg: Reg8Index) -> &'static str {
+    match reg {
+        Reg8Index::A => "a",
+        Reg8Index::B => "b",
+        Reg8Index::C => "c",
+        Reg8Index::D => "d",
+        Reg8Index::E => "e",
+        Reg8Index::HL => "[hl]",
+        Reg8Index::H => "h",
+        Reg8Index::L => "l",
+    }
+}
+
+fn reg16(reg: Reg16Index) -> &'static str {
+    match reg {
+        Reg16Index::BC => "bc",
+        Reg16Index::DE => "de",
+        Reg16Index::HL => "hl",
+        Reg16Index::HLP => "hl+",
+        Reg16Index::HLM => "hl-",
+        Reg16Index::SP => "sp",
+        Reg16Index::AF => "af",
+    }
+}
+
+// In 8-bit instructions, register pairs and 16-bit immediates are addresses.
+fn operand8(op: Operand, r: &mut Reader) -> Option<String> {
+    let text = match op {
+        Operand::Register8(reg) => reg8(reg).into(),
+        Operand::Register16(reg) => format!("[{}]", reg16(reg)),
+        Operand::Value8 => format!("${:02X}", r.u8()?),
+        Operand::Value16 => {
+            let address = r.u16()?;
+            format!("[{}]", r.target(address))
+        }
+    };
+    Some(text)
+}
+
+fn operand16(op: Operand, r: &mut Reader) -> Option<String> {
+    match op {
+        Operand::Register16(reg) => Some(reg16(reg).into()),
+        Operand::Value16 => Some(format!("${:04X}", r.u16()?)),
+        _ => operand8(op, r),
+    }
+}
+
+// Operands of LDH, which address $FF00 onwards.
+fn high_operand(op: Operand, r: &mut Reader) -> Option<String> {
+    match op {
+        Operand::Register8(Reg8Index::C) => Some("[c]".into()),
+        Operand::Value8 => {
+            let address = 0xFF00 | r.u8()? as u16;
+            Some(format!("[{}]", r.target(address)))
+        }
+        _ => operand8(op, r),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (text, length, cycles, taken cycles), indexed by opcode. Operand bytes
+    // are $34 $12 and the instruction sits at $0100.
+    const UNPREFIXED: [(&str, u8, u8, Option<u8>); 256] = [
+        ("nop", 1, 4, None),
+        ("ld bc, $1234", 3, 12, None),
+        ("ld [bc], a", 1, 8, None),
+        ("inc bc", 1, 8, None),
+        ("inc b", 1, 4, None),
+        ("dec b", 1, 4, None),
+        ("ld b, $34", 2, 8, None),
+        ("rlca", 1, 4, None),
+        ("ld [$1234], sp", 3, 20, None),
+        ("add hl, bc", 1, 8, None),
+        ("ld a, [bc]", 1, 8, None),
+        ("dec bc", 1, 8, None),
+        ("inc c", 1, 4, None),
+        ("dec c", 1, 4, None),
+        ("ld c, $34", 2, 8, None),
+        ("rrca", 1, 4, None),
+        ("stop", 2, 4, None),
+        ("ld de, $1234", 3, 12, None),
+        ("ld [de], a", 1, 8, None),
+        ("inc de", 1, 8, None),
+        ("inc d", 1, 4, None),
+        ("dec d", 1, 4, None),
+        ("ld d, $34", 2, 8, None),
+        ("rla", 1, 4, None),
+        ("jr $0136", 2, 12, None),
+        ("add hl, de", 1, 8, None),
+        ("ld a, [de]", 1, 8, None),
+        ("dec de", 1, 8, None),
+        ("inc e", 1, 4, None),
+        ("dec e", 1, 4, None),
+        ("ld e, $34", 2, 8, None),
+        ("rra", 1, 4, None),
+        ("jr nz, $0136", 2, 8, Some(12)),
+        ("ld hl, $1234", 3, 12, None),
+        ("ld [hl+], a", 1, 8, None),
+        ("inc hl", 1, 8, None),
+        ("inc h", 1, 4, None),
+        ("dec h", 1, 4, None),
+        ("ld h, $34", 2, 8, None),
+        ("daa", 1, 4, None),
+        ("jr z, $0136", 2, 8, Some(12)),
+        ("add hl, hl", 1, 8, None),
+        ("ld a, [hl+]", 1, 8, None),
+        ("dec hl", 1, 8, None),
+        ("inc l", 1, 4, None),
+        ("dec l", 1, 4, None),
+        ("ld l, $34", 2, 8, None),
+        ("cpl", 1, 4, None),
+        ("jr nc, $0136", 2, 8, Some(12)),
+        ("ld sp, $1234", 3, 12, None),
+        ("ld [hl-], a", 1, 8, None),
+        ("inc sp", 1, 8, None),
+        ("inc [hl]", 1, 12, None),
+        ("dec [hl]", 1, 12, None),
+        ("ld [hl], $34", 2, 12, None),
+        ("scf", 1, 4, None),
+        ("jr c, $0136", 2, 8, Some(12)),
+        ("add hl, sp", 1, 8, None),
+        ("ld a, [hl-]", 1, 8, None),
+        ("dec sp", 1, 8, None),
+        ("inc a", 1, 4, None),
+        ("dec a", 1, 4, None),
+        ("ld a, $34", 2, 8, None),
+        ("ccf", 1, 4, None),
+        ("ld b, b", 1, 4, None),
+        ("ld b, c", 1, 4, None),
+        ("ld b, d", 1, 4, None),
+        ("ld b, e", 1, 4, None),
+        ("ld b, h", 1, 4, None),
+        ("ld b, l", 1, 4, None),
+        ("ld b, [hl]", 1, 8, None),
+        ("ld b, a", 1, 4, None),
+        ("ld c, b", 1, 4, None),
+        ("ld c, c", 1, 4, None),
+        ("ld c, d", 1, 4, None),
+        ("ld c, e", 1, 4, None),
+        ("ld c, h", 1, 4, None),
+        ("ld c, l", 1, 4, None),
+        ("ld c, [hl]", 1, 8, None),
+        ("ld c, a", 1, 4, None),
+        ("ld d, b", 1, 4, None),
+        ("ld d, c", 1, 4, None),
+        ("ld d, d", 1, 4, None),
+        ("ld d, e", 1, 4, None),
+        ("ld d, h", 1, 4, None),
+        ("ld d, l", 1, 4, None),
+        ("ld d, [hl]", 1, 8, None),
+        ("ld d, a", 1, 4, None),
+        ("ld e, b", 1, 4, None),
+        ("ld e, c", 1, 4, None),
+        ("ld e, d", 1, 4, None),
+        ("ld e, e", 1, 4, None),
+        ("ld e, h", 1, 4, None),
+        ("ld e, l", 1, 4, None),
+        ("ld e, [hl]", 1, 8, None),
+        ("ld e, a", 1, 4, None),
+        ("ld h, b", 1, 4, None),
+        ("ld h, c", 1, 4, None),
+        ("ld h, d", 1, 4, None),
+        ("ld h, e", 1, 4, None),
+        ("ld h, h", 1, 4, None),
+        ("ld h, l", 1, 4, None),
+        ("ld h, [hl]", 1, 8, None),
+        ("ld h, a", 1, 4, None),
+        ("ld l, b", 1, 4, None),
+        ("ld l, c", 1, 4, None),
+        ("ld l, d", 1, 4, None),
+        ("ld l, e", 1, 4, None),
+        ("ld l, h", 1, 4, None),
+        ("ld l, l", 1, 4, None),
+        ("ld l, [hl]", 1, 8, None),
+        ("ld l, a", 1, 4, None),
+        ("ld [hl], b", 1, 8, None),
+        ("ld [hl], c", 1, 8, None),
+        ("ld [hl], d", 1, 8, None),
+        ("ld [hl], e", 1, 8, None),
+        ("ld [hl], h", 1, 8, None),
+        ("ld [hl], l", 1, 8, None),
+        ("halt", 1, 4, None),
+        ("ld [hl], a", 1, 8, None),
+        ("ld a, b", 1, 4, None),
+        ("ld a, c", 1, 4, None),
+        ("ld a, d", 1, 4, None),
+        ("ld a, e", 1, 4, None),
+        ("ld a, h", 1, 4, None),
+        ("ld a, l", 1, 4, None),
+        ("ld a, [hl]", 1, 8, None),
+        ("ld a, a", 1, 4, None),
+        ("add a, b", 1, 4, None),
+        ("add a, c", 1, 4, None),
+        ("add a, d", 1, 4, None),
+        ("add a, e", 1, 4, None),
+        ("add a, h", 1, 4, None),
+        ("add a, l", 1, 4, None),
+        ("add a, [hl]", 1, 8, None),
+        ("add a, a", 1, 4, None),
+        ("adc a, b", 1, 4, None),
+        ("adc a, c", 1, 4, None),
+        ("adc a, d", 1, 4, None),
+        ("adc a, e", 1, 4, None),
+        ("adc a, h", 1, 4, None),
+        ("adc a, l", 1, 4, None),
+        ("adc a, [hl]", 1, 8, None),
+        ("adc a, a", 1, 4, None),
+        ("sub a, b", 1, 4, None),
+        ("sub a, c", 1, 4, None),
+        ("sub a, d", 1, 4, None),
+        ("sub a, e", 1, 4, None),
+        ("sub a, h", 1, 4, None),
+        ("sub a, l", 1, 4, None),
+        ("sub a, [hl]", 1, 8, None),
+        ("sub a, a", 1, 4, None),
+        ("sbc a, b", 1, 4, None),
+        ("sbc a, c", 1, 4, None),
+        ("sbc a, d", 1, 4, None),
+        ("sbc a, e", 1, 4, None),
+        ("sbc a, h", 1, 4, None),
+        ("sbc a, l", 1, 4, None),
+        ("sbc a, [hl]", 1, 8, None),
+        ("sbc a, a", 1, 4, None),
+        ("and a, b", 1, 4, None),
+        ("and a, c", 1, 4, None),
+        ("and a, d", 1, 4, None),
+        ("and a, e", 1, 4, None),
+        ("and a, h", 1, 4, None),
+        ("and a, l", 1, 4, None),
+        ("and a, [hl]", 1, 8, None),
+        ("and a, a", 1, 4, None),
+        ("xor a, b", 1, 4, None),
+        ("xor a, c", 1, 4, None),
+        ("xor a, d", 1, 4, None),
+        ("xor a, e", 1, 4, None),
+        ("xor a, h", 1, 4, None),
+        ("xor a, l", 1, 4, None),
+        ("xor a, [hl]", 1, 8, None),
+        ("xor a, a", 1, 4, None),
+        ("or a, b", 1, 4, None),
+        ("or a, c", 1, 4, None),
+        ("or a, d", 1, 4, None),
+        ("or a, e", 1, 4, None),
+        ("or a, h", 1, 4, None),
+        ("or a, l", 1, 4, None),
+        ("or a, [hl]", 1, 8, None),
+        ("or a, a", 1, 4, None),
+        ("cp a, b", 1, 4, None),
+        ("cp a, c", 1, 4, None),
+        ("cp a, d", 1, 4, None),
+        ("cp a, e", 1, 4, None),
+        ("cp a, h", 1, 4, None),
+        ("cp a, l", 1, 4, None),
+        ("cp a, [hl]", 1, 8, None),
+        ("cp a, a", 1, 4, None),
+        ("ret nz", 1, 8, Some(20)),
+        ("pop bc", 1, 12, None),
+        ("jp nz, $1234", 3, 12, Some(16)),
+        ("jp $1234", 3, 16, None),
+        ("call nz, $1234", 3, 12, Some(24)),
+        ("push bc", 1, 16, None),
+        ("add a, $34", 2, 8, None),
+        ("rst $00", 1, 16, None),
+        ("ret z", 1, 8, Some(20)),
+        ("ret", 1, 16, None),
+        ("jp z, $1234", 3, 12, Some(16)),
+        ("swap h", 2, 8, None),
+        ("call z, $1234", 3, 12, Some(24)),
+        ("call $1234", 3, 24, None),
+        ("adc a, $34", 2, 8, None),
+        ("rst $08", 1, 16, None),
+        ("ret nc", 1, 8, Some(20)),
+        ("pop de", 1, 12, None),
+        ("jp nc, $1234", 3, 12, Some(16)),
+        ("db $D3", 1, 0, None),
+        ("call nc, $1234", 3, 12, Some(24)),
+        ("push de", 1, 16, None),
+        ("sub a, $34", 2, 8, None),
+        ("rst $10", 1, 16, None),
+        ("ret c", 1, 8, Some(20)),
+        ("reti", 1, 16, None),
+        ("jp c, $1234", 3, 12, Some(16)),
+        ("db $DB", 1, 0, None),
+        ("call c, $1234", 3, 12, Some(24)),
+        ("db $DD", 1, 0, None),
+        ("sbc a, $34", 2, 8, None),
+        ("rst $18", 1, 16, None),
+        ("ldh [$FF34], a", 2, 12, None),
+        ("pop hl", 1, 12, None),
+        ("ldh [c], a", 1, 8, None),
+        ("db $E3", 1, 0, None),
+        ("db $E4", 1, 0, None),
+        ("push hl", 1, 16, None),
+        ("and a, $34", 2, 8, None),
+        ("rst $20", 1, 16, None),
+        ("add sp, +$34", 2, 16, None),
+        ("jp hl", 1, 4, None),
+        ("ld [$1234], a", 3, 16, None),
+        ("db $EB", 1, 0, None),
+        ("db $EC", 1, 0, None),
+        ("db $ED", 1, 0, None),
+        ("xor a, $34", 2, 8, None),
+        ("rst $28", 1, 16, None),
+        ("ldh a, [$FF34]", 2, 12, None),
+        ("pop af", 1, 12, None),
+        ("ldh a, [c]", 1, 8, None),
+        ("di", 1, 4, None),
+        ("db $F4", 1, 0, None),
+        ("push af", 1, 16, None),
+        ("or a, $34", 2, 8, None),
+        ("rst $30", 1, 16, None),
+        ("ld hl, sp+$34", 2, 12, None),
+        ("ld sp, hl", 1, 8, None),
+        ("ld a, [$1234]", 3, 16, None),
+        ("ei", 1, 4, None),
+        ("db $FC", 1, 0, None),
+        ("db $FD", 1, 0, None),
+        ("cp a, $34", 2, 8, None),
+        ("rst $38", 1, 16, None),
+    ];
+
+    const PREFIXED: [(&str, u8, u8, Option<u8>); 256] = [
+        ("rlc b", 2, 8, None),
+        ("rlc c", 2, 8, None),
+        ("rlc d", 2, 8, None),
+        ("rlc e", 2, 8, None),
+        ("rlc h", 2, 8, None),
+        ("rlc l", 2, 8, None),
+        ("rlc [hl]", 2, 16, None),
+        ("rlc a", 2, 8, None),
+        ("rrc b", 2, 8, None),
+        ("rrc c", 2, 8, None),
+        ("rrc d", 2, 8, None),
+        ("rrc e", 2, 8, None),
+        ("rrc h", 2, 8, None),
+        ("rrc l", 2, 8, None),
+        ("rrc [hl]", 2, 16, None),
+        ("rrc a", 2, 8, None),
+        ("rl b", 2, 8, None),
+        ("rl c", 2, 8, None),
+        ("rl d", 2, 8, None),
+        ("rl e", 2, 8, None),
+        ("rl h", 2, 8, None),
+        ("rl l", 2, 8, None),
+        ("rl [hl]", 2, 16, None),
+        ("rl a", 2, 8, None),
+        ("rr b", 2, 8, None),
+        ("rr c", 2, 8, None),
+        ("rr d", 2, 8, None),
+        ("rr e", 2, 8, None),
+        ("rr h", 2, 8, None),
+        ("rr l", 2, 8, None),
+        ("rr [hl]", 2, 16, None),
+        ("rr a", 2, 8, None),
+        ("sla b", 2, 8, None),
+        ("sla c", 2, 8, None),
+        ("sla d", 2, 8, None),
+        ("sla e", 2, 8, None),
+        ("sla h", 2, 8, None),
+        ("sla l", 2, 8, None),
+        ("sla [hl]", 2, 16, None),
+        ("sla a", 2, 8, None),
+        ("sra b", 2, 8, None),
+        ("sra c", 2, 8, None),
+        ("sra d", 2, 8, None),
+        ("sra e", 2, 8, None),
+        ("sra h", 2, 8, None),
+        ("sra l", 2, 8, None),
+        ("sra [hl]", 2, 16, None),
+        ("sra a", 2, 8, None),
+        ("swap b", 2, 8, None),
+        ("swap c", 2, 8, None),
+        ("swap d", 2, 8, None),
+        ("swap e", 2, 8, None),
+        ("swap h", 2, 8, None),
+        ("swap l", 2, 8, None),
+        ("swap [hl]", 2, 16, None),
+        ("swap a", 2, 8, None),
+        ("srl b", 2, 8, None),
+        ("srl c", 2, 8, None),
+        ("srl d", 2, 8, None),
+        ("srl e", 2, 8, None),
+        ("srl h", 2, 8, None),
+        ("srl l", 2, 8, None),
+        ("srl [hl]", 2, 16, None),
+        ("srl a", 2, 8, None),
+        ("bit 0, b", 2, 8, None),
+        ("bit 0, c", 2, 8, None),
+        ("bit 0, d", 2, 8, None),
+        ("bit 0, e", 2, 8, None),
+        ("bit 0, h", 2, 8, None),
+        ("bit 0, l", 2, 8, None),
+        ("bit 0, [hl]", 2, 12, None),
+        ("bit 0, a", 2, 8, None),
+        ("bit 1, b", 2, 8, None),
+        ("bit 1, c", 2, 8, None),
+        ("bit 1, d", 2, 8, None),
+        ("bit 1, e", 2, 8, None),
+        ("bit 1, h", 2, 8, None),
+        ("bit 1, l", 2, 8, None),
+        ("bit 1, [hl]", 2, 12, None),
+        ("bit 1, a", 2, 8, None),
+        ("bit 2, b", 2, 8, None),
+        ("bit 2, c", 2, 8, None),
+        ("bit 2, d", 2, 8, None),
+        ("bit 2, e", 2, 8, None),
+        ("bit 2, h", 2, 8, None),
+        ("bit 2, l", 2, 8, None),
+        ("bit 2, [hl]", 2, 12, None),
+        ("bit 2, a", 2, 8, None),
+        ("bit 3, b", 2, 8, None),
+        ("bit 3, c", 2, 8, None),
+        ("bit 3, d", 2, 8, None),
+        ("bit 3, e", 2, 8, None),
+        ("bit 3, h", 2, 8, None),
+        ("bit 3, l", 2, 8, None),
+        ("bit 3, [hl]", 2, 12, None),
+        ("bit 3, a", 2, 8, None),
+        ("bit 4, b", 2, 8, None),
+        ("bit 4, c", 2, 8, None),
+        ("bit 4, d", 2, 8, None),
+        ("bit 4, e", 2, 8, None),
+        ("bit 4, h", 2, 8, None),
+        ("bit 4, l", 2, 8, None),
+        ("bit 4, [hl]", 2, 12, None),
+        ("bit 4, a", 2, 8, None),
+        ("bit 5, b", 2, 8, None),
+        ("bit 5, c", 2, 8, None),
+        ("bit 5, d", 2, 8, None),
+        ("bit 5, e", 2, 8, None),
+        ("bit 5, h", 2, 8, None),
+        ("bit 5, l", 2, 8, None),
+        ("bit 5, [hl]", 2, 12, None),
+        ("bit 5, a", 2, 8, None),
+        ("bit 6, b", 2, 8, None),
+        ("bit 6, c", 2, 8, None),
+        ("bit 6, d", 2, 8, None),
+        ("bit 6, e", 2, 8, None),
+        ("bit 6, h", 2, 8, None),
+        ("bit 6, l", 2, 8, None),
+        ("bit 6, [hl]", 2, 12, None),
+        ("bit 6, a", 2, 8, None),
+        ("bit 7, b", 2, 8, None),
+        ("bit 7, c", 2, 8, None),
+        ("bit 7, d", 2, 8, None),
+        ("bit 7, e", 2, 8, None),
+        ("bit 7, h", 2, 8, None),
+        ("bit 7, l", 2, 8, None),
+        ("bit 7, [hl]", 2, 12, None),
+        ("bit 7, a", 2, 8, None),
+        ("res 0, b", 2, 8, None),
+        ("res 0, c", 2, 8, None),
+        ("res 0, d", 2, 8, None),
+        ("res 0, e", 2, 8, None),
+        ("res 0, h", 2, 8, None),
+        ("res 0, l", 2, 8, None),
+        ("res 0, [hl]", 2, 16, None),
+        ("res 0, a", 2, 8, None),
+        ("res 1, b", 2, 8, None),
+        ("res 1, c", 2, 8, None),
+        ("res 1, d", 2, 8, None),
+        ("res 1, e", 2, 8, None),
+        ("res 1, h", 2, 8, None),
+        ("res 1, l", 2, 8, None),
+        ("res 1, [hl]", 2, 16, None),
+        ("res 1, a", 2, 8, None),
+        ("res 2, b", 2, 8, None),
+        ("res 2, c", 2, 8, None),
+        ("res 2, d", 2, 8, None),
+        ("res 2, e", 2, 8, None),
+        ("res 2, h", 2, 8, None),
+        ("res 2, l", 2, 8, None),
+        ("res 2, [hl]", 2, 16, None),
+        ("res 2, a", 2, 8, None),
+        ("res 3, b", 2, 8, None),
+        ("res 3, c", 2, 8, None),
+        ("res 3, d", 2, 8, None),
+        ("res 3, e", 2, 8, None),
+        ("res 3, h", 2, 8, None),
+        ("res 3, l", 2, 8, None),
+        ("res 3, [hl]", 2, 16, None),
+        ("res 3, a", 2, 8, None),
+        ("res 4, b", 2, 8, None),
+        ("res 4, c", 2, 8, None),
+        ("res 4, d", 2, 8, None),
+        ("res 4, e", 2, 8, None),
+        ("res 4, h", 2, 8, None),
+        ("res 4, l", 2, 8, None),
+        ("res 4, [hl]", 2, 16, None),
+        ("res 4, a", 2, 8, None),
+        ("res 5, b", 2, 8, None),
+        ("res 5, c", 2, 8, None),
+        ("res 5, d", 2, 8, None),
+        ("res 5, e", 2, 8, None),
+        ("res 5, h", 2, 8, None),
+        ("res 5, l", 2, 8, None),
+        ("res 5, [hl]", 2, 16, None),
+        ("res 5, a", 2, 8, None),
+        ("res 6, b", 2, 8, None),
+        ("res 6, c", 2, 8, None),
+        ("res 6, d", 2, 8, None),
+        ("res 6, e", 2, 8, None),
+        ("res 6, h", 2, 8, None),
+        ("res 6, l", 2, 8, None),
+        ("res 6, [hl]", 2, 16, None),
+        ("res 6, a", 2, 8, None),
+        ("res 7, b", 2, 8, None),
+        ("res 7, c", 2, 8, None),
+        ("res 7, d", 2, 8, None),
+        ("res 7, e", 2, 8, None),
+        ("res 7, h", 2, 8, None),
+        ("res 7, l", 2, 8, None),
+        ("res 7, [hl]", 2, 16, None),
+        ("res 7, a", 2, 8, None),
+        ("set 0, b", 2, 8, None),
+        ("set 0, c", 2, 8, None),
+        ("set 0, d", 2, 8, None),
+        ("set 0, e", 2, 8, None),
+        ("set 0, h", 2, 8, None),
+        ("set 0, l", 2, 8, None),
+        ("set 0, [hl]", 2, 16, None),
+        ("set 0, a", 2, 8, None),
+        ("set 1, b", 2, 8, None),
+        ("set 1, c", 2, 8, None),
+        ("set 1, d", 2, 8, None),
+        ("set 1, e", 2, 8, None),
+        ("set 1, h", 2, 8, None),
+        ("set 1, l", 2, 8, None),
+        ("set 1, [hl]", 2, 16, None),
+        ("set 1, a", 2, 8, None),
+        ("set 2, b", 2, 8, None),
+        ("set 2, c", 2, 8, None),
+        ("set 2, d", 2, 8, None),
+        ("set 2, e", 2, 8, None),
+        ("set 2, h", 2, 8, None),
+        ("set 2, l", 2, 8, None),
+        ("set 2, [hl]", 2, 16, None),
+        ("set 2, a", 2, 8, None),
+        ("set 3, b", 2, 8, None),
+        ("set 3, c", 2, 8, None),
+        ("set 3, d", 2, 8, None),
+        ("set 3, e", 2, 8, None),
+        ("set 3, h", 2, 8, None),
+        ("set 3, l", 2, 8, None),
+        ("set 3, [hl]", 2, 16, None),
+        ("set 3, a", 2, 8, None),
+        ("set 4, b", 2, 8, None),
+        ("set 4, c", 2, 8, None),
+        ("set 4, d", 2, 8, None),
+        ("set 4, e", 2, 8, None),
+        ("set 4, h", 2, 8, None),
+        ("set 4, l", 2, 8, None),
+        ("set 4, [hl]", 2, 16, None),
+        ("set 4, a", 2, 8, None),
+        ("set 5, b", 2, 8, None),
+        ("set 5, c", 2, 8, None),
+        ("set 5, d", 2, 8, None),
+        ("set 5, e", 2, 8, None),
+        ("set 5, h", 2, 8, None),
+        ("set 5, l", 2, 8, None),
+        ("set 5, [hl]", 2, 16, None),
+        ("set 5, a", 2, 8, None),
+        ("set 6, b", 2, 8, None),
+        ("set 6, c", 2, 8, None),
+        ("set 6, d", 2, 8, None),
+        ("set 6, e", 2, 8, None),
+        ("set 6, h", 2, 8, None),
+        ("set 6, l", 2, 8, None),
+        ("set 6, [hl]", 2, 16, None),
+        ("set 6, a", 2, 8, None),
+        ("set 7, b", 2, 8, None),
+        ("set 7, c", 2, 8, None),
+        ("set 7, d", 2, 8, None),
+        ("set 7, e", 2, 8, None),
+        ("set 7, h", 2, 8, None),
+        ("set 7, l", 2, 8, None),
+        ("set 7, [hl]", 2, 16, None),
+        ("set 7, a", 2, 8, None),
+    ];
+
+    fn check(bytes: &[u8], expected: &(&str, u8, u8, Option<u8>)) {
+        let dis = disassemble(bytes, 0x0100).unwrap();
+        assert_eq!(
+            (dis.text.as_str(), dis.length, dis.cycles, dis.taken_cycles),
+            *expected,
+            "{:02X?}",
+            bytes
+        );
+    }
+
+    #[test]
+    fn every_opcode() {
+        for (opcode, expected) in UNPREFIXED.iter().enumerate() {
+            check(&[opcode as u8, 0x34, 0x12], expected);
+        }
+        for (opcode, expected) in PREFIXED.iter().enumerate() {
+            check(&[0xCB, opcode as u8], expected);
+        }
+    }
+
+    #[test]
+    fn labels_from_symbols() {
+        let symbols = Symbols::parse("00:0150 Main\n02:4000 Far\n00:FF80 hFlag\n");
+        let text = |bytes: &[u8], bank| {
+            disassemble_with_symbols(bytes, 0x0100, bank, &symbols)
+                .unwrap()
+                .text
+        };
+        assert_eq!(text(&[0xCD, 0x50, 0x01], 1), "call Main");
+        assert_eq!(text(&[0xC3, 0x00, 0x40], 2), "jp Far");
+        assert_eq!(text(&[0xC3, 0x00, 0x40], 1), "jp $4000");
+        assert_eq!(text(&[0xE0, 0x80], 1), "ldh [hFlag], a");
+    }
+
+    #[test]
+    fn negative_offsets_and_short_input() {
+        let text = |bytes: &[u8], address| disassemble(bytes, address).unwrap().text;
+        assert_eq!(text(&[0x18, 0xFE], 0x0150), "jr $0150");
+        assert_eq!(text(&[0x38, 0x80], 0x0000), "jr c, $FF82");
+        assert_eq!(text(&[0xF8, 0xFF], 0x0000), "ld hl, sp-$01");
+        assert_eq!(text(&[0xE8, 0x80], 0x0000), "add sp, -$80");
+        assert_eq!(disassemble(&[0xC3, 0x00], 0x0000), None);
+        assert_eq!(disassemble(&[0xCB], 0x0000), None);
+        assert_eq!(disassemble(&[], 0x0000), None);
+    }
+}
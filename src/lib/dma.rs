@@ -1,6 +1,9 @@
+use crate::error::ErrorKind;
 use crate::{
     device::IOHandler,
     mmu::{MemoryBus, MemoryRead, MemoryWrite},
+    scheduler::Event,
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
 
 pub struct DMA {
@@ -16,13 +19,21 @@ impl DMA {
         }
     }
 
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
     pub fn step(&mut self, bus: &mut MemoryBus) {
         if self.active {
             self.active = false;
             let src = (self.reg as u16) << 8;
             // println!("Start DMA Transfer from {:04X}", src);
             for idx in 0..160 {
-                let value = bus.read_byte(src + idx).unwrap();
+                let address = src + idx;
+                let value = bus.read_byte(address).unwrap_or_else(|| {
+                    bus.fault(ErrorKind::UnmappedRead { address });
+                    0xFF
+                });
                 bus.write_byte(0xFE00 + idx, value);
             }
         }
@@ -37,7 +48,27 @@ impl IOHandler for DMA {
         if address == 0xFF46 {
             self.active = true;
             self.reg = value;
+            mmu.scheduler().schedule(Event::Dma, mmu.scheduler().now());
         }
         MemoryWrite::Block
     }
+    // Sets the source register without starting a transfer.
+    fn poke(&mut self, _mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        if address == 0xFF46 {
+            self.reg = value;
+        }
+        MemoryWrite::Block
+    }
+}
+
+impl SaveState for DMA {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.reg);
+        w.bool(self.active);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.reg = r.u8()?;
+        self.active = r.bool()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,73 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::debug::{Frame, Location, StackWarning};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnmappedRead { address: u16 },
+    UnmappedWrite { address: u16 },
+    // A device was accessed while it was already borrowed.
+    RecursiveAccess { address: u16 },
+    RomOutOfRange { address: u16, offset: u32 },
+    RamOutOfRange { address: u16, offset: u32 },
+    // A host write to ROM. The ROM is not part of save states, so patching
+    // it would break rewind, movies and state hashes.
+    ReadOnly { address: u16 },
+}
+
+// An emulation failure. The System is left as it was at the point of the
+// failure, so the host can still inspect it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmuError {
+    pub kind: ErrorKind,
+    // Address of the instruction that was executing.
+    pub pc: u16,
+    // The shadow call stack, innermost last.
+    pub call_stack: Vec<Frame>,
+    // The last instructions executed, oldest first.
+    pub history: Vec<Location>,
+    pub warnings: Vec<StackWarning>,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::UnmappedRead { address } => write!(f, "cannot read {:04X}", address),
+            ErrorKind::UnmappedWrite { address } => write!(f, "cannot write {:04X}", address),
+            ErrorKind::RecursiveAccess { address } => {
+                write!(f, "recursive device access at {:04X}", address)
+            }
+            ErrorKind::RomOutOfRange { address, offset } => write!(
+                f,
+                "ROM read at {:04X} is past the end of the ROM (offset {:06X})",
+                address, offset
+            ),
+            ErrorKind::RamOutOfRange { address, offset } => write!(
+                f,
+                "cartridge RAM access at {:04X} is out of range (offset {:06X})",
+                address, offset
+            ),
+            ErrorKind::ReadOnly { address } => write!(f, "cannot poke ROM at {:04X}", address),
+        }
+    }
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (PC={:04X})", self.kind, self.pc)?;
+        for frame in self.call_stack.iter().rev() {
+            write!(f, "\n  in {}", frame)?;
+        }
+        for warning in &self.warnings {
+            write!(f, "\n  warning: {}", warning)?;
+        }
+        if !self.history.is_empty() {
+            write!(f, "\n  recent:")?;
+            for location in &self.history {
+                write!(f, " {}", location)?;
+            }
+        }
+        Ok(())
+    }
+}
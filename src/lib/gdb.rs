@@ -0,0 +1,508 @@
+use alloc::{format, string::String, vec::Vec};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::debug::{Breakpoint, WatchKind, Watchpoint};
+use crate::system::{BreakReason, System};
+
+// GDB has no SM83 target, so the register layout is described to it by
+// target.xml: the eight 8-bit registers, then SP and PC as 16-bit little
+// endian values.
+const REGISTER_COUNT: usize = 10;
+const TARGET_XML: &str = concat!(
+    "<?xml version=\"1.0\"?>",
+    "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+    "<target version=\"1.0\"><feature name=\"org.gnu.gdb.sm83.core\">",
+    "<reg name=\"a\" bitsize=\"8\" regnum=\"0\"/>",
+    "<reg name=\"f\" bitsize=\"8\"/>",
+    "<reg name=\"b\" bitsize=\"8\"/>",
+    "<reg name=\"c\" bitsize=\"8\"/>",
+    "<reg name=\"d\" bitsize=\"8\"/>",
+    "<reg name=\"e\" bitsize=\"8\"/>",
+    "<reg name=\"h\" bitsize=\"8\"/>",
+    "<reg name=\"l\" bitsize=\"8\"/>",
+    "<reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\"/>",
+    "<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>",
+    "</feature></target>",
+);
+
+pub trait Connection: Read + Write {
+    // Checks, without blocking, whether the debugger sent an interrupt
+    // (Ctrl-C) while the target was running.
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Err(ErrorKind::UnexpectedEof.into()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// A GDB remote serial protocol server for one debugger session.
+pub struct GdbStub<C: Connection> {
+    conn: C,
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(conn: C) -> GdbStub<C> {
+        GdbStub { conn }
+    }
+
+    // Serves requests until the debugger detaches or kills the session, or
+    // the host shuts down.
+    pub fn serve(&mut self, system: &mut System) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'c') => {
+                    if let Some(pc) = parse_hex(&packet[1..]) {
+                        system.cancel_step();
+                        self.set_pc(system, pc as u16);
+                    }
+                    self.resume(system)?
+                }
+                Some(b's') => {
+                    if let Some(pc) = parse_hex(&packet[1..]) {
+                        self.set_pc(system, pc as u16);
+                    }
+                    match system.step_into() {
+                        Ok(summary) => stop_reply(summary.reason),
+                        Err(_) => "S0B".into(),
+                    }
+                }
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => handle(system, &packet),
+            };
+            if reply == "W00" {
+                return self.write_packet(&reply);
+            }
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn set_pc(&self, system: &mut System, pc: u16) {
+        let mut state = system.cpu_state();
+        state.pc = pc;
+        system.set_cpu_state(state);
+    }
+
+    // Runs frame by frame until something stops the target, checking for an
+    // interrupt from the debugger in between.
+    fn resume(&mut self, system: &mut System) -> io::Result<String> {
+        loop {
+            let summary = match system.run_frame() {
+                Ok(summary) => summary,
+                Err(_) => return Ok("S0B".into()),
+            };
+            match summary.reason {
+                BreakReason::Frame
+                | BreakReason::CycleBudget
+                | BreakReason::LcdOff
+                | BreakReason::Stopped => {}
+                reason => return Ok(stop_reply(reason)),
+            }
+            if !system.is_active() {
+                return Ok("W00".into());
+            }
+            if self.conn.poll_interrupt()? {
+                return Ok("S02".into());
+            }
+        }
+    }
+
+    // Returns None once the connection is closed. Interrupts that arrive
+    // while the target is already stopped are ignored.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            loop {
+                if self.conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                if self.conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.conn.read_exact(&mut checksum)?;
+            let expected = core::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            if expected != Some(sum(&data)) {
+                self.conn.write_all(b"-")?;
+                continue;
+            }
+            self.conn.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, sum(data.as_bytes()));
+        self.conn.write_all(packet.as_bytes())?;
+        self.conn.flush()?;
+        // Wait for the acknowledgement, resending on a request to.
+        let mut ack = [0];
+        loop {
+            if self.conn.read(&mut ack)? == 0 {
+                return Ok(());
+            }
+            match ack[0] {
+                b'+' => return Ok(()),
+                b'-' => self.conn.write_all(packet.as_bytes())?,
+                _ => {}
+            }
+        }
+    }
+}
+
+// Everything that does not run the target.
+fn handle(system: &mut System, packet: &str) -> String {
+    if !packet.is_char_boundary(1) {
+        return String::new();
+    }
+    let (command, args) = packet.split_at(1);
+    match command {
+        "?" => "S05".into(),
+        "g" => registers(system)
+            .iter()
+            .enumerate()
+            .map(|(n, value)| format_register(n, *value))
+            .collect(),
+        "G" => match parse_registers(args) {
+            Some(values) => {
+                set_registers(system, &values);
+                "OK".into()
+            }
+            None => "E01".into(),
+        },
+        "p" => match parse_hex(args).filter(|n| (*n as usize) < REGISTER_COUNT) {
+            Some(n) => format_register(n as usize, registers(system)[n as usize]),
+            None => "E01".into(),
+        },
+        "P" => {
+            let parsed = args.split_once('=').and_then(|(n, value)| {
+                let n = parse_hex(n).filter(|n| (*n as usize) < REGISTER_COUNT)? as usize;
+                Some((n, parse_register(n, value)?))
+            });
+            match parsed {
+                Some((n, value)) => {
+                    let mut values = registers(system);
+                    values[n] = value;
+                    set_registers(system, &values);
+                    "OK".into()
+                }
+                None => "E01".into(),
+            }
+        }
+        "m" => match parse_range(args) {
+            Some((address, len)) => (0..len)
+                .map(|offset| format!("{:02x}", system.peek(address.wrapping_add(offset))))
+                .collect(),
+            None => "E01".into(),
+        },
+        "M" => {
+            let parsed = args
+                .split_once(':')
+                .and_then(|(range, data)| Some((parse_range(range)?, parse_bytes(data)?)));
+            match parsed {
+                Some(((address, len), data)) if data.len() == len as usize => {
+                    let written = data
+                        .into_iter()
+                        .enumerate()
+                        .try_for_each(|(offset, value)| {
+                            system.poke(address.wrapping_add(offset as u16), value)
+                        });
+                    match written {
+                        Ok(()) => "OK".into(),
+                        Err(_) => "E01".into(),
+                    }
+                }
+                _ => "E01".into(),
+            }
+        }
+        "Z" | "z" => match parse_point(args) {
+            Some((kind, address, len)) => set_point(system, command == "Z", kind, address, len),
+            None => "E01".into(),
+        },
+        "H" => "OK".into(),
+        "q" => match args {
+            _ if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+".into(),
+            _ if args.starts_with("Xfer:features:read:target.xml:") => {
+                match parse_range(&args["Xfer:features:read:target.xml:".len()..]) {
+                    Some((offset, len)) => read_xfer(TARGET_XML, offset, len),
+                    None => "E01".into(),
+                }
+            }
+            "Attached" => "1".into(),
+            "C" => "QC1".into(),
+            "fThreadInfo" => "m1".into(),
+            "sThreadInfo" => "l".into(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+fn registers(system: &System) -> [u16; REGISTER_COUNT] {
+    let s = system.cpu_state();
+    [
+        s.a as u16, s.f as u16, s.b as u16, s.c as u16, s.d as u16, s.e as u16, s.h as u16,
+        s.l as u16, s.sp, s.pc,
+    ]
+}
+
+fn set_registers(system: &mut System, values: &[u16; REGISTER_COUNT]) {
+    let mut s = system.cpu_state();
+    let [a, f, b, c, d, e, h, l, sp, pc] = *values;
+    [s.a, s.f, s.b, s.c, s.d, s.e, s.h, s.l] = [a, f, b, c, d, e, h, l].map(|value| value as u8);
+    s.sp = sp;
+    s.pc = pc;
+    system.set_cpu_state(s);
+}
+
+// In bytes, as laid out in target.xml.
+fn register_size(n: usize) -> usize {
+    if n < 8 {
+        1
+    } else {
+        2
+    }
+}
+
+fn format_register(n: usize, value: u16) -> String {
+    value.to_le_bytes()[..register_size(n)]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// "m" followed by the requested part of an annex, or "l" if it is the last.
+fn read_xfer(annex: &str, offset: u16, len: u16) -> String {
+    let start = (offset as usize).min(annex.len());
+    let end = (start + len as usize).min(annex.len());
+    let more = if end < annex.len() { 'm' } else { 'l' };
+    format!("{}{}", more, &annex[start..end])
+}
+
+// Breakpoint types 0 and 1 are software and hardware breakpoints, which are
+// the same thing here; 2, 3 and 4 are write, read and access watchpoints.
+fn set_point(system: &mut System, insert: bool, kind: u32, address: u16, len: u16) -> String {
+    let watch = match kind {
+        0 | 1 => {
+            let breakpoint = Breakpoint {
+                address,
+                bank: None,
+            };
+            match insert {
+                true => system.add_breakpoint(breakpoint),
+                false => {
+                    system.remove_breakpoint(breakpoint);
+                }
+            }
+            return "OK".into();
+        }
+        2 => WatchKind::Write,
+        3 => WatchKind::Read,
+        4 => WatchKind::Access,
+        _ => return String::new(),
+    };
+    let watchpoint = Watchpoint {
+        start: address,
+        end: address.wrapping_add(len.max(1) - 1),
+        kind: watch,
+        value: None,
+    };
+    match insert {
+        true => system.add_watchpoint(watchpoint),
+        false => {
+            system.remove_watchpoint(watchpoint);
+        }
+    }
+    "OK".into()
+}
+
+fn stop_reply(reason: BreakReason) -> String {
+    match reason {
+        BreakReason::Watchpoint(hit) => {
+            let kind = if hit.write { "watch" } else { "rwatch" };
+            format!("T05{}:{:04x};", kind, hit.address)
+        }
+        _ => "S05".into(),
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(text.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+// A register value, sent little endian.
+fn parse_register(n: usize, text: &str) -> Option<u16> {
+    match parse_bytes(text)?.as_slice() {
+        [low] if register_size(n) == 1 => Some(*low as u16),
+        [low, high] if register_size(n) == 2 => Some(u16::from_le_bytes([*low, *high])),
+        _ => None,
+    }
+}
+
+fn parse_registers(text: &str) -> Option<[u16; REGISTER_COUNT]> {
+    let mut values = [0; REGISTER_COUNT];
+    let mut at = 0;
+    for (n, value) in values.iter_mut().enumerate() {
+        let len = register_size(n) * 2;
+        *value = parse_register(n, text.get(at..at + len)?)?;
+        at += len;
+    }
+    Some(values)
+}
+
+// "addr,length"
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (address, len) = text.split_once(',')?;
+    Some((parse_hex(address)? as u16, parse_hex(len)? as u16))
+}
+
+// "type,addr,kind"
+fn parse_point(text: &str) -> Option<(u32, u16, u16)> {
+    let (kind, range) = text.split_once(',')?;
+    let (address, len) = parse_range(range)?;
+    Some((parse_hex(kind)?, address, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::tests::system;
+    use std::io::Cursor;
+
+    // Replays what a debugger sent and collects what the stub answered.
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn poll_interrupt(&mut self) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}+", data, sum(data.as_bytes()))
+    }
+
+    #[test]
+    fn session() {
+        // loop: inc a; jr loop
+        let mut system = system(&[0x3C, 0x18, 0xFD]);
+        let requests = [
+            "qSupported:xmlRegisters=i386",
+            "qXfer:features:read:target.xml:0,20",
+            "qXfer:features:read:target.xml:20,1000",
+            "g",
+            "m100,3",
+            "Z0,101,1",
+            "c",
+            "s",
+            "z0,101,1",
+            "Mc000,2:beef",
+            "mc000,2",
+            "P0=ff",
+            "p0",
+            "P8=f0ff",
+            "p8",
+            "D",
+        ];
+        let input: String = requests.iter().map(|r| packet(r)).collect();
+        let mut stub = GdbStub::new(Script {
+            input: Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        });
+        stub.serve(&mut system).unwrap();
+
+        let output = String::from_utf8(stub.conn.output).unwrap();
+        let replies: Vec<&str> = output
+            .split('$')
+            .skip(1)
+            .map(|reply| reply.split('#').next().unwrap())
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=1000;qXfer:features:read+",
+                &format!("m{}", &TARGET_XML[..0x20]),
+                &format!("l{}", &TARGET_XML[0x20..]),
+                "01b0001300d8014dfeff0001",
+                "3c18fd",
+                "OK",
+                "S05",
+                "S05",
+                "OK",
+                "OK",
+                "beef",
+                "OK",
+                "ff",
+                "OK",
+                "f0ff",
+                "OK",
+            ]
+        );
+        let state = system.cpu_state();
+        assert_eq!(
+            (state.a, state.f, state.sp, state.pc),
+            (0xFF, 0x10, 0xFFF0, 0x0100)
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::device::IOHandler;
-use crate::hardware::HardwareHandle;
 use crate::mmu::{MemoryBus, MemoryRead, MemoryWrite};
+use crate::scheduler::{Event, Scheduler};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub type RenderCallback = fn(&FrameBuffer);
 
@@ -54,6 +55,13 @@ pub struct GPU {
     OBP0: u8,
     OBP1: u8,
     ppu_dot: u16,
+    frames: u64,
+    last_sync: u64,
+    vblank: bool,
+    // Frozen while the CPU is in STOP mode.
+    stopped: bool,
+    // LY always reads $90, as the gameboy-doctor reference logs assume.
+    doctor: bool,
 
     frame_buffer: FrameBuffer,
 }
@@ -90,31 +98,92 @@ impl GPU {
             OBP0: 0,
             OBP1: 0,
             ppu_dot: 0,
+            frames: 0,
+            last_sync: 0,
+            vblank: false,
+            stopped: false,
+            doctor: false,
             frame_buffer: FrameBuffer {
                 pixels: [Pixel::Black; FRAME_HEIGHT * FRAME_WIDTH],
             },
         }
     }
-    pub fn step(&mut self, elapsed_cycles: u16, bus: &mut MemoryBus, hw: &HardwareHandle) {
-        for _ in 0..elapsed_cycles {
-            if self.LCDC & 0x80 != 0 {
-                self.ppu_dot += 1;
-                if self.ppu_dot == 456 {
-                    if self.LY < 144 {
-                        self.render();
-                    }
-                    if self.LY == 143 {
-                        bus.set_if(bus.get_if() | 1);
-                        hw.get().borrow_mut().draw_framebuffer(&self.frame_buffer);
-                    }
-                    self.LY = (self.LY + 1) % 154;
-                    self.ppu_dot = 0;
-                }
-            } else {
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn set_doctor_mode(&mut self, doctor: bool) {
+        self.doctor = doctor;
+    }
+
+    // The current line, even in doctor mode.
+    pub fn ly(&mut self, scheduler: &Scheduler) -> u8 {
+        self.sync(scheduler);
+        self.LY
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.LCDC & 0x80 != 0
+    }
+
+    pub fn frame_buffer(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+
+    // Returns true once after a frame has been completed, so the caller can
+    // raise the VBlank interrupt and present the frame.
+    pub fn take_vblank(&mut self) -> bool {
+        core::mem::replace(&mut self.vblank, false)
+    }
+
+    // Catches the PPU up to the scheduler's current time, one line at a time,
+    // and schedules the end of the current line.
+    pub fn sync(&mut self, scheduler: &Scheduler) {
+        let now = scheduler.now();
+        let mut elapsed = now - self.last_sync;
+        self.last_sync = now;
+        if self.stopped {
+            scheduler.cancel(Event::Ppu);
+            return;
+        }
+        if self.LCDC & 0x80 == 0 {
+            if elapsed != 0 {
                 self.LY = 0;
                 self.ppu_dot = 0;
             }
+            scheduler.cancel(Event::Ppu);
+            return;
+        }
+        while elapsed != 0 {
+            let line_left = (456 - self.ppu_dot) as u64;
+            if elapsed < line_left {
+                self.ppu_dot += elapsed as u16;
+                break;
+            }
+            elapsed -= line_left;
+            if self.LY < 144 {
+                self.render();
+            }
+            if self.LY == 143 {
+                self.frames += 1;
+                self.vblank = true;
+            }
+            self.LY = (self.LY + 1) % 154;
+            self.ppu_dot = 0;
         }
+        scheduler.schedule(Event::Ppu, now + (456 - self.ppu_dot) as u64);
+    }
+
+    // Forgets any time that passed before `now`, e.g. after loading a state.
+    pub fn resync(&mut self, scheduler: &Scheduler) {
+        self.last_sync = scheduler.now();
+        self.sync(scheduler);
+    }
+
+    pub fn set_stopped(&mut self, stopped: bool, scheduler: &Scheduler) {
+        self.sync(scheduler);
+        self.stopped = stopped;
+        self.sync(scheduler);
     }
 
     pub fn render(&mut self) {
@@ -177,7 +246,8 @@ impl GPU {
 }
 
 impl IOHandler for GPU {
-    fn read(&mut self, _mmu: &MemoryBus, address: u16) -> MemoryRead {
+    fn read(&mut self, mmu: &MemoryBus, address: u16) -> MemoryRead {
+        self.sync(mmu.scheduler());
         match address {
             0x8000..=0x97FF => unsafe {
                 MemoryRead::Value(
@@ -196,6 +266,7 @@ impl IOHandler for GPU {
             0xFF41 => MemoryRead::Value(self.STAT),
             0xFF42 => MemoryRead::Value(self.SCY),
             0xFF43 => MemoryRead::Value(self.SCX),
+            0xFF44 if self.doctor => MemoryRead::Value(0x90),
             0xFF44 => MemoryRead::Value(self.LY),
             0xFF45 => MemoryRead::Value(self.LYC),
             0xFF47 => MemoryRead::Value(self.BGP),
@@ -206,7 +277,8 @@ impl IOHandler for GPU {
             _ => MemoryRead::PassThrough,
         }
     }
-    fn write(&mut self, _mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+    fn write(&mut self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        self.sync(mmu.scheduler());
         match address {
             0x8000..=0x97FF => unsafe {
                 *(&mut self.tiles[0] as *mut Tile as *mut u8).offset((address & 0x1FFF) as isize) =
@@ -230,6 +302,83 @@ impl IOHandler for GPU {
             0xFF4B => self.WX = value,
             _ => return MemoryWrite::PassThrough,
         }
+        if address == 0xFF40 {
+            // Turning the LCD on or off changes when the next line ends.
+            self.sync(mmu.scheduler());
+        }
         MemoryWrite::Value(value)
     }
 }
+
+impl SaveState for GPU {
+    fn save(&self, w: &mut StateWriter) {
+        for tile in &self.tiles {
+            w.bytes(&tile.pixels);
+        }
+        w.bytes(&self.tile_map);
+        for sprite in &self.oam {
+            w.bytes(&[sprite.y, sprite.x, sprite.tile_index, sprite.attribute]);
+        }
+        w.bytes(&[
+            self.LCDC, self.STAT, self.LY, self.LYC, self.WY, self.WX, self.SCY, self.SCX,
+            self.BGP, self.OBP0, self.OBP1,
+        ]);
+        w.u16(self.ppu_dot);
+        w.u64(self.frames);
+        // Four 2-bit shades per byte; the frame is usually half drawn.
+        for pixels in self.frame_buffer.pixels.chunks(4) {
+            let mut packed = 0;
+            for (idx, pixel) in pixels.iter().enumerate() {
+                packed |= (*pixel as u8) << (idx * 2);
+            }
+            w.u8(packed);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for tile in self.tiles.iter_mut() {
+            r.bytes(&mut tile.pixels)?;
+        }
+        r.bytes(&mut self.tile_map)?;
+        for sprite in self.oam.iter_mut() {
+            let mut raw = [0; 4];
+            r.bytes(&mut raw)?;
+            *sprite = Sprite {
+                y: raw[0],
+                x: raw[1],
+                tile_index: raw[2],
+                attribute: raw[3],
+            };
+        }
+        let mut regs = [0; 11];
+        r.bytes(&mut regs)?;
+        let [lcdc, stat, ly, lyc, wy, wx, scy, scx, bgp, obp0, obp1] = regs;
+        self.LCDC = lcdc;
+        self.STAT = stat;
+        self.LY = ly;
+        self.LYC = lyc;
+        self.WY = wy;
+        self.WX = wx;
+        self.SCY = scy;
+        self.SCX = scx;
+        self.BGP = bgp;
+        self.OBP0 = obp0;
+        self.OBP1 = obp1;
+        self.ppu_dot = r.u16()?;
+        self.frames = r.u64()?;
+        if self.LY >= 154 || self.ppu_dot >= 456 {
+            return Err(StateError::Corrupted);
+        }
+        for pixels in self.frame_buffer.pixels.chunks_mut(4) {
+            let packed = r.u8()?;
+            for (idx, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = match (packed >> (idx * 2)) & 0x03 {
+                    0 => Pixel::Black,
+                    1 => Pixel::Dark,
+                    2 => Pixel::Bright,
+                    _ => Pixel::White,
+                };
+            }
+        }
+        Ok(())
+    }
+}
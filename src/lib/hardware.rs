@@ -2,12 +2,17 @@ use core::cell::RefCell;
 
 use alloc::rc::Rc;
 
+use crate::input::JoypadState;
 use crate::FrameBuffer;
 
 pub trait Hardware {
     fn is_active(&mut self) -> bool;
     fn draw_framebuffer(&mut self, frame_buffer: &FrameBuffer);
-    fn get_keys(&mut self) -> (u8, u8);
+    // Polled after every instruction. Hosts that push timestamped events through
+    // `System::queue_input` instead can leave this returning None.
+    fn get_keys(&mut self) -> Option<JoypadState> {
+        None
+    }
     fn update(&mut self);
 }
 
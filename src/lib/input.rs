@@ -1,12 +1,95 @@
+use alloc::collections::VecDeque;
+
 use crate::device::IOHandler;
-use crate::hardware::HardwareHandle;
 use crate::mmu::{MemoryBus, MemoryRead, MemoryWrite};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    // Low nibble is the direction group (P14), high nibble the action group
+    // (P15), both in P10-P13 order.
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// Set of buttons currently held.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JoypadState(u8);
+
+impl JoypadState {
+    pub fn new() -> JoypadState {
+        JoypadState(0)
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.0 & button.mask() != 0
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match pressed {
+            true => self.0 |= button.mask(),
+            false => self.0 &= !button.mask(),
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.set(button, true);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.set(button, false);
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u8) -> JoypadState {
+        JoypadState(bits)
+    }
+}
+
+// A press or release that takes effect once emulation reaches `cycle`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    pub cycle: u64,
+    pub button: Button,
+    pub pressed: bool,
+}
 
 pub struct Pad {
     cross_button: u8,
     ab_button: u8,
+    // Selected when the matching P1 bit is written as 0. Both groups can be
+    // selected at once, in which case their lines are ANDed.
     cross_select: bool,
     ab_select: bool,
+    interrupt: bool,
+    // Pending events, ordered by cycle.
+    queue: VecDeque<InputEvent>,
 }
 
 impl Pad {
@@ -16,41 +99,201 @@ impl Pad {
             ab_button: 0x00,
             cross_select: false,
             ab_select: false,
+            interrupt: false,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> JoypadState {
+        JoypadState(self.cross_button | self.ab_button << 4)
+    }
+
+    // P10-P13, active low.
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.cross_select {
+            pressed |= self.cross_button;
+        }
+        if self.ab_select {
+            pressed |= self.ab_button;
+        }
+        !pressed & 0x0F
+    }
+
+    // Raises the joypad interrupt if any line went from high to low.
+    fn update(&mut self, change: impl FnOnce(&mut Pad)) {
+        let before = self.lines();
+        change(self);
+        if before & !self.lines() != 0 {
+            self.interrupt = true;
+        }
+    }
+
+    pub fn set_state(&mut self, state: JoypadState) {
+        self.update(|pad| {
+            pad.cross_button = state.0 & 0x0F;
+            pad.ab_button = state.0 >> 4;
+        });
+    }
+
+    // Events at the same cycle are applied in the order they were queued.
+    pub fn queue(&mut self, event: InputEvent) {
+        let idx = self
+            .queue
+            .partition_point(|queued| queued.cycle <= event.cycle);
+        self.queue.insert(idx, event);
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+
+    pub fn next_event(&self) -> Option<u64> {
+        self.queue.front().map(|event| event.cycle)
+    }
+
+    pub fn apply_events(&mut self, now: u64) {
+        let mut state = self.state();
+        self.drain_events(now, &mut state);
+        self.set_state(state);
+    }
+
+    // Removes the next event if it is due by `now`.
+    pub fn pop_event(&mut self, now: u64) -> Option<InputEvent> {
+        match self.queue.front() {
+            Some(event) if event.cycle <= now => self.queue.pop_front(),
+            _ => None,
         }
     }
 
-    pub fn step(&mut self, hw: &HardwareHandle) {
-        let (cross_input, ab_input) = hw.get().borrow_mut().get_keys();
-        self.cross_button = cross_input;
-        self.ab_button = ab_input;
+    // Applies the events that are due to `state` rather than to the pad.
+    pub fn drain_events(&mut self, now: u64, state: &mut JoypadState) {
+        while let Some(event) = self.pop_event(now) {
+            state.set(event.button, event.pressed);
+        }
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        core::mem::replace(&mut self.interrupt, false)
     }
 }
 
 impl IOHandler for Pad {
     fn read(&mut self, mmu: &MemoryBus, address: u16) -> MemoryRead {
-        if self.cross_select {
-            MemoryRead::Value(!(0x10 | self.cross_button))
-        } else if self.ab_select {
-            MemoryRead::Value(!(0x20 | self.ab_button))
+        let select = (!self.cross_select as u8) << 4 | (!self.ab_select as u8) << 5;
+        MemoryRead::Value(0xC0 | select | self.lines())
+    }
+    fn write(&mut self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        if address == 0xFF00 {
+            self.update(|pad| {
+                pad.cross_select = value & 0x10 == 0;
+                pad.ab_select = value & 0x20 == 0;
+            });
+            MemoryWrite::Value(value)
         } else {
-            MemoryRead::Value(0xFF)
+            MemoryWrite::PassThrough
         }
     }
-    fn write(&mut self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+    // Selects lines without raising the joypad interrupt.
+    fn poke(&mut self, _mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
         if address == 0xFF00 {
-            if value & 0x10 == 0 {
-                self.cross_select = true;
-                self.ab_select = false;
-            } else if value & 0x20 == 0 {
-                self.cross_select = false;
-                self.ab_select = true;
-            } else {
-                self.cross_select = false;
-                self.ab_select = false;
-            }
+            self.cross_select = value & 0x10 == 0;
+            self.ab_select = value & 0x20 == 0;
             MemoryWrite::Value(value)
         } else {
             MemoryWrite::PassThrough
         }
     }
 }
+
+impl SaveState for Pad {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.cross_button);
+        w.u8(self.ab_button);
+        w.bool(self.cross_select);
+        w.bool(self.ab_select);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.cross_button = r.u8()?;
+        self.ab_button = r.u8()?;
+        self.cross_select = r.bool()?;
+        self.ab_select = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(pad: &mut Pad, bus: &MemoryBus) -> u8 {
+        match pad.read(bus, 0xFF00) {
+            MemoryRead::Value(value) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn state(buttons: &[Button]) -> JoypadState {
+        let mut state = JoypadState::new();
+        for button in buttons {
+            state.press(*button);
+        }
+        state
+    }
+
+    #[test]
+    fn p1_read_back() {
+        let bus = MemoryBus::new();
+        let mut pad = Pad::new();
+        pad.set_state(state(&[Button::Right, Button::Start]));
+        pad.write(&bus, 0xFF00, 0x30);
+        assert_eq!(read(&mut pad, &bus), 0xFF);
+        pad.write(&bus, 0xFF00, 0x20);
+        assert_eq!(read(&mut pad, &bus), 0xEE);
+        pad.write(&bus, 0xFF00, 0x10);
+        assert_eq!(read(&mut pad, &bus), 0xD7);
+        pad.write(&bus, 0xFF00, 0x00);
+        assert_eq!(read(&mut pad, &bus), 0xC6);
+    }
+
+    #[test]
+    fn interrupt_on_press() {
+        let bus = MemoryBus::new();
+        let mut pad = Pad::new();
+        pad.write(&bus, 0xFF00, 0x20);
+        pad.set_state(state(&[Button::A]));
+        assert!(!pad.take_interrupt());
+        pad.set_state(state(&[Button::Up, Button::A]));
+        assert!(pad.take_interrupt());
+        // Holding or releasing does not fire again.
+        pad.set_state(state(&[Button::Up, Button::A]));
+        pad.set_state(state(&[Button::A]));
+        assert!(!pad.take_interrupt());
+        // Selecting a group with a button held is also a falling edge.
+        pad.write(&bus, 0xFF00, 0x00);
+        assert!(pad.take_interrupt());
+    }
+
+    #[test]
+    fn events_apply_at_their_cycle() {
+        let mut pad = Pad::new();
+        let event = |cycle, button, pressed| InputEvent {
+            cycle,
+            button,
+            pressed,
+        };
+        pad.queue(event(200, Button::A, false));
+        pad.queue(event(100, Button::A, true));
+        pad.queue(event(100, Button::Left, true));
+        assert_eq!(pad.next_event(), Some(100));
+
+        pad.apply_events(99);
+        assert_eq!(pad.state(), JoypadState::new());
+        pad.apply_events(150);
+        assert_eq!(pad.state(), state(&[Button::A, Button::Left]));
+        assert_eq!(pad.next_event(), Some(200));
+        pad.apply_events(200);
+        assert_eq!(pad.state(), state(&[Button::Left]));
+        assert_eq!(pad.next_event(), None);
+    }
+}
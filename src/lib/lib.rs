@@ -1,22 +1,54 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
+mod cdl;
 mod cpu;
 mod cycle;
+mod debug;
 mod device;
+mod disasm;
 mod dma;
+mod error;
+#[cfg(feature = "std")]
+mod gdb;
 mod gpu;
 mod hardware;
 mod input;
 mod inst;
 mod mbc;
 mod mmu;
+mod movie;
+mod profile;
+mod recorder;
 mod register;
+mod rewind;
+mod scheduler;
 mod sound;
+mod state;
+mod symbols;
 mod system;
 
+pub use cdl::{CodeDataLog, Coverage, CDL_CODE, CDL_DATA, CDL_OPERAND};
+pub use cpu::CpuState;
+pub use debug::{
+    Breakpoint, Frame, FrameKind, Location, StackWarning, TraceEntry, WatchHit, WatchKind,
+    Watchpoint,
+};
+pub use disasm::{disassemble, disassemble_with_symbols, Disassembly};
+pub use error::{EmuError, ErrorKind};
+#[cfg(feature = "std")]
+pub use gdb::{Connection, GdbStub};
 pub use gpu::{FrameBuffer, Pixel, FRAME_HEIGHT, FRAME_WIDTH};
 pub use hardware::Hardware;
+pub use input::{Button, InputEvent, JoypadState};
 pub use mbc::Cartridge;
-pub use system::{run, System};
+pub use movie::{Movie, MovieStatus};
+pub use profile::{Grouping, ProfileEntry, Profiler};
+pub use recorder::{Access, AccessCount, AccessRecorder, Heatmap, RecorderConfig};
+pub use rewind::RewindConfig;
+pub use state::StateError;
+pub use symbols::{Symbol, Symbols};
+pub use system::{run, BreakReason, RunSummary, System, FRAME_CYCLES};
@@ -1,38 +1,81 @@
 use crate::device::IOHandler;
+use crate::error::ErrorKind;
 use crate::mmu::{MemoryRead, MemoryWrite};
-use alloc::vec::Vec;
+use crate::state::{self, SaveState, StateError, StateReader, StateWriter};
+use alloc::{rc::Rc, vec::Vec};
 
 pub struct Cartridge {
-    rom: Vec<u8>,
+    rom: Rc<[u8]>,
     ram: Vec<u8>,
     reg: [u8; 4],
     rom_bank: u32,
     ram_bank: u32,
+    rom_hash: u32,
 }
 
 impl Cartridge {
     pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Cartridge {
         Cartridge {
-            rom: rom,
+            rom_hash: state::hash(&rom),
+            rom: rom.into(),
             ram: ram,
             reg: [0, 1, 0, 0],
             rom_bank: 0x4000,
             ram_bank: 0x0000,
         }
     }
+
+    // Shared with the bus, which reads ROM without going through the handler.
+    pub fn rom(&self) -> Rc<[u8]> {
+        self.rom.clone()
+    }
+
+    pub fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => (address & 0x3FFF) as usize + self.rom_bank as usize,
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        (address & 0x1FFF) as usize + self.ram_bank as usize
+    }
 }
 
 impl IOHandler for Cartridge {
     fn read(&mut self, mmu: &crate::mmu::MemoryBus, address: u16) -> MemoryRead {
-        match address {
-            0x0000..=0x3FFF => MemoryRead::Value(self.rom[address as usize]),
-            0x4000..=0x7FFF => {
-                MemoryRead::Value(self.rom[((address & 0x3FFF) as u32 + self.rom_bank) as usize])
-            }
+        let offset = match address {
+            0x0000..=0x3FFF => address as u32,
+            0x4000..=0x7FFF => (address & 0x3FFF) as u32 + self.rom_bank,
             0xA000..=0xBFFF => {
-                MemoryRead::Value(self.ram[((address & 0x1FFF) as u32 + self.ram_bank) as usize])
+                let offset = (address & 0x1FFF) as u32 + self.ram_bank;
+                return match self.ram.get(offset as usize) {
+                    Some(value) => MemoryRead::Value(*value),
+                    None => {
+                        mmu.fault(ErrorKind::RamOutOfRange { address, offset });
+                        MemoryRead::Value(0xFF)
+                    }
+                };
+            }
+            _ => return MemoryRead::PassThrough,
+        };
+        match self.rom.get(offset as usize) {
+            Some(value) => {
+                mmu.log_rom(offset as usize);
+                MemoryRead::Value(*value)
+            }
+            None => {
+                mmu.fault(ErrorKind::RomOutOfRange { address, offset });
+                MemoryRead::Value(0xFF)
             }
-            _ => MemoryRead::PassThrough,
         }
     }
     fn write(&mut self, mmu: &crate::mmu::MemoryBus, address: u16, value: u8) -> MemoryWrite {
@@ -42,6 +85,7 @@ impl IOHandler for Cartridge {
                 // println!("rom bank changed {}", value);
                 self.reg[1] = value;
                 self.rom_bank = (if value != 0 { (value & 0x3F) as u32 } else { 1 }) << 14;
+                mmu.set_rom_bank(self.rom_bank as usize);
             }
             0x4000..=0x5FFF => {
                 self.reg[2] = value;
@@ -51,10 +95,64 @@ impl IOHandler for Cartridge {
             }
             0x6000..=0x7FFF => self.reg[3] = value,
             0xA000..=0xBFFF => {
-                self.ram[((address & 0x1FFF) as u32 + self.ram_bank) as usize] = value;
+                let offset = (address & 0x1FFF) as u32 + self.ram_bank;
+                match self.ram.get_mut(offset as usize) {
+                    Some(byte) => *byte = value,
+                    None => mmu.fault(ErrorKind::RamOutOfRange { address, offset }),
+                }
             }
             _ => return MemoryWrite::PassThrough,
         }
         MemoryWrite::PassThrough
     }
+    fn peek(&mut self, _mmu: &crate::mmu::MemoryBus, address: u16) -> MemoryRead {
+        match address {
+            0x0000..=0x7FFF => MemoryRead::Value(
+                self.rom
+                    .get(self.rom_offset(address))
+                    .copied()
+                    .unwrap_or(0xFF),
+            ),
+            0xA000..=0xBFFF => MemoryRead::Value(
+                self.ram
+                    .get(self.ram_offset(address))
+                    .copied()
+                    .unwrap_or(0xFF),
+            ),
+            _ => MemoryRead::PassThrough,
+        }
+    }
+    fn poke(&mut self, _mmu: &crate::mmu::MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        match address {
+            // The ROM is never patched; System::poke refuses it.
+            0x0000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                let offset = self.ram_offset(address);
+                if let Some(byte) = self.ram.get_mut(offset) {
+                    *byte = value;
+                }
+            }
+            _ => return MemoryWrite::PassThrough,
+        }
+        MemoryWrite::Block
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.reg);
+        w.u32(self.rom_bank);
+        w.u32(self.ram_bank);
+        w.u32(self.ram.len() as u32);
+        w.bytes(&self.ram);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.reg)?;
+        self.rom_bank = r.u32()?;
+        self.ram_bank = r.u32()?;
+        if r.u32()? as usize != self.ram.len() {
+            return Err(StateError::Corrupted);
+        }
+        r.bytes(&mut self.ram)
+    }
 }
@@ -1,9 +1,50 @@
-use alloc::{rc::Rc, vec, vec::Vec};
-use hashbrown::HashMap;
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use core::cell::{Cell, RefCell};
+
+use crate::cdl::{CodeDataLog, CDL_DATA};
+use crate::debug::{Location, WatchHit, Watchpoint};
+use crate::error::ErrorKind;
+use crate::recorder::{Access, AccessRecorder};
+use crate::scheduler::{Event, Scheduler};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const NO_CHAIN: u16 = u16::MAX;
+
+// Reads the line the PPU is on, as the recorder sees it.
+pub type LineSource = Box<dyn Fn(&Scheduler) -> u8>;
+
+// How the 256 addresses of one page are dispatched.
+#[derive(Clone, Copy)]
+enum Page {
+    // Plain bus memory, no handlers.
+    Memory,
+    // The same handler chain for the whole page.
+    Chain(u16),
+    // A chain (or NO_CHAIN) per address, for pages shared by several devices.
+    Sparse(u16),
+}
 
 pub struct MemoryBus {
     memory: [u8; 0x10000],
-    handlers: HashMap<u16, Vec<Rc<dyn MemoryHandler>>>,
+    pages: [Page; 0x100],
+    chains: Vec<Vec<Rc<dyn MemoryHandler>>>,
+    sparse: Vec<[u16; 0x100]>,
+    rom: Option<Rc<[u8]>>,
+    rom_bank: Cell<usize>,
+    scheduler: Scheduler,
+    fault: Cell<Option<ErrorKind>>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>,
+    cdl: Option<CodeDataLog>,
+    // How ROM reads are logged; the CPU changes it while fetching.
+    cdl_access: Cell<u8>,
+    recorder: Option<RefCell<AccessRecorder>>,
+    // Kept up to date for the recorder by the System and the CPU. The PC is
+    // only set while the CPU runs an instruction, and only the accesses made
+    // then are recorded.
+    frame: Cell<u64>,
+    cpu_pc: Cell<Option<u16>>,
+    line: Option<LineSource>,
 }
 
 pub enum MemoryRead {
@@ -20,49 +61,334 @@ pub enum MemoryWrite {
 pub trait MemoryHandler {
     fn read(&self, mmu: &MemoryBus, address: u16) -> MemoryRead;
     fn write(&self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite;
+
+    fn peek(&self, mmu: &MemoryBus, address: u16) -> MemoryRead {
+        self.read(mmu, address)
+    }
+    fn poke(&self, mmu: &MemoryBus, address: u16, value: u8) -> MemoryWrite {
+        self.write(mmu, address, value)
+    }
+}
+
+fn memory_index(address: u16) -> usize {
+    if (0xE000..=0xFDFF).contains(&address) {
+        (address - 0x2000) as usize
+    } else {
+        address as usize
+    }
 }
 
 impl MemoryBus {
     pub fn new() -> MemoryBus {
         MemoryBus {
             memory: [0; 0x10000],
-            handlers: HashMap::new(),
+            pages: [Page::Memory; 0x100],
+            chains: Vec::new(),
+            sparse: Vec::new(),
+            rom: None,
+            rom_bank: Cell::new(0x4000),
+            scheduler: Scheduler::new(),
+            fault: Cell::new(None),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            cdl: None,
+            cdl_access: Cell::new(CDL_DATA),
+            recorder: None,
+            frame: Cell::new(0),
+            cpu_pc: Cell::new(None),
+            line: None,
+        }
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    // Handlers report failures here instead of panicking; the first one is
+    // kept until the CPU picks it up at the end of the instruction.
+    pub fn fault(&self, kind: ErrorKind) {
+        if self.fault.get().is_none() {
+            self.fault.set(Some(kind));
         }
     }
+
+    pub fn take_fault(&self) -> Option<ErrorKind> {
+        self.fault.take()
+    }
+
+    pub fn watchpoints(&mut self) -> &mut Vec<Watchpoint> {
+        &mut self.watchpoints
+    }
+
+    pub fn is_watching(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    // Called for CPU accesses. Like faults, only the first hit of an
+    // instruction is kept.
+    pub fn watch(&self, address: u16, value: u8, write: bool) {
+        if self.watch_hit.get().is_none()
+            && self
+                .watchpoints
+                .iter()
+                .any(|w| w.matches(address, value, write))
+        {
+            self.watch_hit.set(Some(WatchHit {
+                address,
+                value,
+                write,
+            }));
+        }
+    }
+
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub fn set_cdl(&mut self, cdl: Option<CodeDataLog>) -> Option<CodeDataLog> {
+        core::mem::replace(&mut self.cdl, cdl)
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cdl.as_ref()
+    }
+
+    pub fn set_access(&self, flag: u8) {
+        self.cdl_access.set(flag);
+    }
+
+    // Called for reads of ROM at physical `offset`.
+    pub fn log_rom(&self, offset: usize) {
+        if let Some(cdl) = &self.cdl {
+            cdl.mark(offset, self.cdl_access.get());
+        }
+    }
+
+    pub fn set_recorder(&mut self, recorder: Option<AccessRecorder>) -> Option<AccessRecorder> {
+        core::mem::replace(&mut self.recorder, recorder.map(RefCell::new)).map(RefCell::into_inner)
+    }
+
+    pub fn recorder(&mut self) -> Option<&mut AccessRecorder> {
+        self.recorder.as_mut().map(RefCell::get_mut)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Without a source, LY is read through the bus.
+    pub fn set_line_source(&mut self, line: Option<LineSource>) {
+        self.line = line;
+    }
+
+    pub fn set_frame(&self, frame: u64) {
+        self.frame.set(frame);
+    }
+
+    pub fn set_cpu_pc(&self, pc: Option<u16>) {
+        self.cpu_pc.set(pc);
+    }
+
+    // Must not be called while a handler is running: reading LY syncs the
+    // PPU, which would find itself already borrowed.
+    fn record(&self, address: u16, value: u8, write: bool) {
+        let (Some(recorder), Some(pc)) = (&self.recorder, self.cpu_pc.get()) else {
+            return;
+        };
+        if !recorder.borrow().wants(address, write) {
+            return;
+        }
+        let ly = match &self.line {
+            Some(line) => line(&self.scheduler),
+            None => self.peek(0xFF44),
+        };
+        // The PPU schedules the end of the current line while the LCD is on.
+        let now = self.scheduler.now();
+        let dot = match self.scheduler.deadline(Event::Ppu) {
+            Some(end) => 456 - end.saturating_sub(now).clamp(1, 456) as u16,
+            None => 0,
+        };
+        recorder.borrow_mut().record(Access {
+            frame: self.frame.get(),
+            ly,
+            dot,
+            pc,
+            address,
+            value,
+            write,
+        });
+    }
+
     pub fn add_handler<T>(&mut self, range: (u16, u16), handler: T)
     where
         T: MemoryHandler + 'static,
     {
-        let handler = Rc::new(handler);
-        for addr in range.0..=range.1 {
-            if self.handlers.contains_key(&addr) {
-                match self.handlers.get_mut(&addr) {
-                    Some(v) => v.push(handler.clone()),
-                    None => {}
+        let handler: Rc<dyn MemoryHandler> = Rc::new(handler);
+        // Every distinct chain the range overlaps gets one extended copy.
+        let mut extended: Vec<(u16, u16)> = Vec::new();
+        let mut extend = |bus: &mut MemoryBus, old: u16| -> u16 {
+            if let Some((_, to)) = extended.iter().find(|(from, _)| *from == old) {
+                return *to;
+            }
+            let mut chain = match old {
+                NO_CHAIN => Vec::new(),
+                _ => bus.chains[old as usize].clone(),
+            };
+            chain.push(handler.clone());
+            bus.chains.push(chain);
+            let to = (bus.chains.len() - 1) as u16;
+            extended.push((old, to));
+            to
+        };
+        for page in (range.0 >> 8)..=(range.1 >> 8) {
+            let first = range.0.max(page << 8);
+            let last = range.1.min((page << 8) | 0xFF);
+            match self.pages[page as usize] {
+                Page::Memory if first & 0xFF == 0 && last & 0xFF == 0xFF => {
+                    self.pages[page as usize] = Page::Chain(extend(self, NO_CHAIN));
+                }
+                Page::Chain(old) if first & 0xFF == 0 && last & 0xFF == 0xFF => {
+                    self.pages[page as usize] = Page::Chain(extend(self, old));
+                }
+                _ => {
+                    for addr in first..=last {
+                        let old = self.chain_at(addr);
+                        let new = extend(self, old);
+                        self.set_chain(addr, new);
+                    }
+                    self.compact(page as usize);
                 }
-            } else {
-                self.handlers.insert(addr, vec![handler.clone()]);
             }
         }
     }
+
+    fn chain_at(&self, address: u16) -> u16 {
+        match self.pages[(address >> 8) as usize] {
+            Page::Memory => NO_CHAIN,
+            Page::Chain(chain) => chain,
+            Page::Sparse(idx) => self.sparse[idx as usize][(address & 0xFF) as usize],
+        }
+    }
+
+    fn set_chain(&mut self, address: u16, chain: u16) {
+        let page = (address >> 8) as usize;
+        let idx = match self.pages[page] {
+            Page::Sparse(idx) => idx,
+            Page::Memory => self.new_sparse(NO_CHAIN),
+            Page::Chain(old) => self.new_sparse(old),
+        };
+        self.pages[page] = Page::Sparse(idx);
+        self.sparse[idx as usize][(address & 0xFF) as usize] = chain;
+    }
+
+    fn new_sparse(&mut self, fill: u16) -> u16 {
+        self.sparse.push([fill; 0x100]);
+        (self.sparse.len() - 1) as u16
+    }
+
+    // Collapses a sparse page back to a single entry if it is uniform.
+    // The stale sparse table is left behind; this only runs at setup.
+    fn compact(&mut self, page: usize) {
+        if let Page::Sparse(idx) = self.pages[page] {
+            let table = &self.sparse[idx as usize];
+            if table.iter().all(|chain| *chain == table[0]) {
+                self.pages[page] = match table[0] {
+                    NO_CHAIN => Page::Memory,
+                    chain => Page::Chain(chain),
+                };
+            }
+        }
+    }
+
+    // Serves reads of 0x0000-0x7FFF straight from `rom`, with 0x4000-0x7FFF
+    // following the bank set by `set_rom_bank`. Writes still go to handlers.
+    pub fn map_rom(&mut self, rom: Rc<[u8]>) {
+        self.rom = Some(rom);
+    }
+
+    pub fn set_rom_bank(&self, offset: usize) {
+        self.rom_bank.set(offset);
+    }
+
+    // The ROM bank mapped at `address`, or None outside of ROM.
+    pub fn rom_bank_at(&self, address: u16) -> Option<u16> {
+        match address {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some((self.rom_bank.get() / 0x4000) as u16),
+            _ => None,
+        }
+    }
+
+    pub fn location(&self, address: u16) -> Location {
+        Location {
+            bank: self.rom_bank_at(address).unwrap_or(0),
+            address,
+        }
+    }
+
     pub fn read_byte(&self, address: u16) -> Option<u8> {
-        if let Some(handlers) = self.handlers.get(&address) {
-            for handler in handlers {
+        let value = self.read_mapped(address);
+        if let (Some(value), true) = (value, self.is_recording()) {
+            self.record(address, value, false);
+        }
+        value
+    }
+
+    fn read_mapped(&self, address: u16) -> Option<u8> {
+        if address < 0x8000 {
+            if let Some(rom) = &self.rom {
+                let idx = match address {
+                    0x0000..=0x3FFF => address as usize,
+                    _ => (address & 0x3FFF) as usize + self.rom_bank.get(),
+                };
+                return match rom.get(idx) {
+                    Some(byte) => {
+                        self.log_rom(idx);
+                        Some(*byte)
+                    }
+                    None => {
+                        self.fault(ErrorKind::RomOutOfRange {
+                            address,
+                            offset: idx as u32,
+                        });
+                        Some(0xFF)
+                    }
+                };
+            }
+        }
+        let chain = match self.pages[(address >> 8) as usize] {
+            Page::Memory => return Some(self.memory[memory_index(address)]),
+            Page::Chain(chain) => chain,
+            Page::Sparse(idx) => self.sparse[idx as usize][(address & 0xFF) as usize],
+        };
+        if chain != NO_CHAIN {
+            for handler in &self.chains[chain as usize] {
                 match handler.read(self, address) {
                     MemoryRead::Value(val) => return Some(val),
                     MemoryRead::PassThrough => {}
                 }
             }
         }
-        if address >= 0xE000 && address <= 0xFDFF {
-            Some(self.memory[(address - 0x2000) as usize])
-        } else {
-            Some(self.memory[address as usize])
-        }
+        Some(self.memory[memory_index(address)])
     }
+
     pub fn write_byte(&mut self, address: u16, value: u8) -> Option<()> {
-        if let Some(handlers) = self.handlers.get(&address) {
-            for handler in handlers {
+        if self.is_recording() {
+            self.record(address, value, true);
+        }
+        self.write_mapped(address, value)
+    }
+
+    // A write with all of its side effects, but hidden from the recorder.
+    // For the emulator's own accesses, which the game did not make.
+    pub fn write_mapped(&mut self, address: u16, value: u8) -> Option<()> {
+        let chain = match self.pages[(address >> 8) as usize] {
+            Page::Memory => NO_CHAIN,
+            Page::Chain(chain) => chain,
+            Page::Sparse(idx) => self.sparse[idx as usize][(address & 0xFF) as usize],
+        };
+        if chain != NO_CHAIN {
+            for handler in &self.chains[chain as usize] {
                 match handler.write(self, address, value) {
                     MemoryWrite::Value(val) => {
                         self.memory[address as usize] = val;
@@ -73,14 +399,50 @@ impl MemoryBus {
                 }
             }
         }
-        if address >= 0xE000 && address <= 0xFDFF {
-            self.memory[(address - 0x2000) as usize] = value;
-        } else {
-            self.memory[address as usize] = value;
-        }
+        self.memory[memory_index(address)] = value;
         Some(())
     }
 
+    // Like `read_byte`, but through the handlers' side effect free `peek`.
+    pub fn peek(&self, address: u16) -> u8 {
+        if address < 0x8000 {
+            if let Some(rom) = &self.rom {
+                let idx = match address {
+                    0x0000..=0x3FFF => address as usize,
+                    _ => (address & 0x3FFF) as usize + self.rom_bank.get(),
+                };
+                return rom.get(idx).copied().unwrap_or(0xFF);
+            }
+        }
+        let chain = self.chain_at(address);
+        if chain != NO_CHAIN {
+            for handler in &self.chains[chain as usize] {
+                match handler.peek(self, address) {
+                    MemoryRead::Value(val) => return val,
+                    MemoryRead::PassThrough => {}
+                }
+            }
+        }
+        self.memory[memory_index(address)]
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        let chain = self.chain_at(address);
+        if chain != NO_CHAIN {
+            for handler in &self.chains[chain as usize] {
+                match handler.poke(self, address, value) {
+                    MemoryWrite::Value(val) => {
+                        self.memory[address as usize] = val;
+                        return;
+                    }
+                    MemoryWrite::PassThrough => {}
+                    MemoryWrite::Block => return,
+                }
+            }
+        }
+        self.memory[memory_index(address)] = value;
+    }
+
     pub fn get_ie(&self) -> u8 {
         self.memory[0xffff]
     }
@@ -93,3 +455,77 @@ impl MemoryBus {
         self.memory[0xff0f] = value;
     }
 }
+
+// Only the regions that are backed by the bus itself (WRAM, OAM shadow, I/O
+// and HRAM) are saved; everything else lives in a device.
+const SAVED_REGIONS: [(usize, usize); 2] = [(0xC000, 0xDFFF), (0xFE00, 0xFFFF)];
+
+impl SaveState for MemoryBus {
+    fn save(&self, w: &mut StateWriter) {
+        for (start, end) in SAVED_REGIONS {
+            w.bytes(&self.memory[start..=end]);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for (start, end) in SAVED_REGIONS {
+            r.bytes(&mut self.memory[start..=end])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(u16, u8);
+
+    impl MemoryHandler for Fixed {
+        fn read(&self, _mmu: &MemoryBus, address: u16) -> MemoryRead {
+            match address == self.0 {
+                true => MemoryRead::Value(self.1),
+                false => MemoryRead::PassThrough,
+            }
+        }
+        fn write(&self, _mmu: &MemoryBus, _address: u16, _value: u8) -> MemoryWrite {
+            MemoryWrite::Block
+        }
+    }
+
+    #[test]
+    fn sparse_page_dispatch() {
+        let mut bus = MemoryBus::new();
+        bus.add_handler((0xFF40, 0xFF40), Fixed(0xFF40, 0x91));
+        bus.add_handler((0xFF00, 0xFF7F), Fixed(0xFF41, 0x85));
+        bus.write_byte(0xFF80, 0x12);
+        bus.write_byte(0xFF40, 0x00);
+
+        assert_eq!(bus.read_byte(0xFF40), Some(0x91));
+        assert_eq!(bus.read_byte(0xFF41), Some(0x85));
+        assert_eq!(bus.read_byte(0xFF42), Some(0x00));
+        assert_eq!(bus.read_byte(0xFF80), Some(0x12));
+    }
+
+    #[test]
+    fn echo_and_rom_fast_paths() {
+        let mut bus = MemoryBus::new();
+        bus.write_byte(0xE123, 0x42);
+        assert_eq!(bus.read_byte(0xC123), Some(0x42));
+
+        let rom: Rc<[u8]> = (0..0x10000).map(|i| (i >> 14) as u8).collect();
+        bus.map_rom(rom);
+        assert_eq!(bus.read_byte(0x0000), Some(0));
+        assert_eq!(bus.read_byte(0x4000), Some(1));
+        bus.set_rom_bank(3 << 14);
+        assert_eq!(bus.read_byte(0x7FFF), Some(3));
+        bus.set_rom_bank(4 << 14);
+        assert_eq!(bus.read_byte(0x4000), Some(0xFF));
+        assert_eq!(
+            bus.take_fault(),
+            Some(ErrorKind::RomOutOfRange {
+                address: 0x4000,
+                offset: 0x10000
+            })
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use alloc::{vec, vec::Vec};
+
+use crate::input::{Button, InputEvent, JoypadState};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const MAGIC: [u8; 4] = *b"RGBM";
+const MOVIE_VERSION: u16 = 1;
+// Frames between two state hashes.
+pub const HASH_INTERVAL: usize = 60;
+
+// A recorded session: the state it started from, the joypad state latched
+// at every VBlank after that and the queued input events, at the cycle they
+// reached the pad. State hashes taken every `HASH_INTERVAL` frames let
+// playback notice when it has drifted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    rom_hash: u32,
+    start: Vec<u8>,
+    frames: Vec<JoypadState>,
+    // Cycles count from the start state.
+    events: Vec<InputEvent>,
+    hashes: Vec<(u64, u32)>,
+}
+
+impl Movie {
+    pub(crate) fn new(rom_hash: u32, start: Vec<u8>) -> Movie {
+        Movie {
+            rom_hash,
+            start,
+            frames: Vec::new(),
+            events: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub(crate) fn start(&self) -> &[u8] {
+        &self.start
+    }
+
+    pub(crate) fn frame(&self, frame: usize) -> Option<JoypadState> {
+        self.frames.get(frame).copied()
+    }
+
+    pub(crate) fn push(&mut self, keys: JoypadState) {
+        self.frames.push(keys);
+    }
+
+    pub(crate) fn event(&self, idx: usize) -> Option<InputEvent> {
+        self.events.get(idx).copied()
+    }
+
+    pub(crate) fn push_event(&mut self, event: InputEvent) {
+        self.events.push(event);
+    }
+
+    // Drops everything recorded after `frames` frames and `cycle` cycles.
+    pub(crate) fn truncate(&mut self, frames: usize, cycle: u64) {
+        self.frames.truncate(frames);
+        self.events.retain(|event| event.cycle <= cycle);
+        self.hashes.retain(|(at, _)| *at < frames as u64);
+    }
+
+    pub(crate) fn push_hash(&mut self, frame: usize, hash: u32) {
+        self.hashes.push((frame as u64, hash));
+    }
+
+    pub(crate) fn hash_at(&self, frame: usize) -> Option<u32> {
+        let idx = self.hashes.partition_point(|(at, _)| *at < frame as u64);
+        match self.hashes.get(idx) {
+            Some((at, hash)) if *at == frame as u64 => Some(*hash),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::raw();
+        w.bytes(&MAGIC);
+        w.u16(MOVIE_VERSION);
+        w.u32(self.rom_hash);
+        w.u32(self.start.len() as u32);
+        w.bytes(&self.start);
+        w.u32(self.frames.len() as u32);
+        for keys in &self.frames {
+            w.u8(keys.bits());
+        }
+        w.u32(self.events.len() as u32);
+        for event in &self.events {
+            w.u64(event.cycle);
+            w.u8(event.button as u8);
+            w.bool(event.pressed);
+        }
+        w.u32(self.hashes.len() as u32);
+        for (frame, hash) in &self.hashes {
+            w.u64(*frame);
+            w.u32(*hash);
+        }
+        w.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, StateError> {
+        let mut r = StateReader::raw(data);
+        let mut magic = [0; 4];
+        r.bytes(&mut magic)?;
+        if magic != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != MOVIE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let rom_hash = r.u32()?;
+        let len = r.u32()? as usize;
+        if len > r.remaining() {
+            return Err(StateError::Truncated);
+        }
+        let mut start = vec![0; len];
+        r.bytes(&mut start)?;
+        let mut movie = Movie::new(rom_hash, start);
+        for _ in 0..r.u32()? {
+            movie.push(JoypadState::from_bits(r.u8()?));
+        }
+        for _ in 0..r.u32()? {
+            let cycle = r.u64()?;
+            let button = *Button::ALL
+                .get(r.u8()? as usize)
+                .ok_or(StateError::Corrupted)?;
+            movie.push_event(InputEvent {
+                cycle,
+                button,
+                pressed: r.bool()?,
+            });
+        }
+        for _ in 0..r.u32()? {
+            let frame = r.u64()?;
+            movie.hashes.push((frame, r.u32()?));
+        }
+        r.finish()?;
+        Ok(movie)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MovieStatus {
+    pub recording: bool,
+    // Frames latched since the movie started.
+    pub frame: usize,
+    pub length: usize,
+    // First frame whose state hash did not match the recording.
+    pub desync: Option<usize>,
+    // Playback ran past the last frame; host input is used again.
+    pub finished: bool,
+}
+
+enum Mode {
+    // Holds the input that will be latched at the next VBlank.
+    Recording(JoypadState),
+    Playing,
+    Finished,
+}
+
+pub(crate) struct MovieSession {
+    movie: Movie,
+    mode: Mode,
+    frame: usize,
+    // Next recorded event to replay.
+    event: usize,
+    desync: Option<usize>,
+    // Cycle of the start state.
+    start: u64,
+}
+
+impl MovieSession {
+    pub fn record(movie: Movie, keys: JoypadState, start: u64) -> MovieSession {
+        MovieSession {
+            movie,
+            mode: Mode::Recording(keys),
+            frame: 0,
+            event: 0,
+            desync: None,
+            start,
+        }
+    }
+
+    pub fn play(movie: Movie, start: u64) -> MovieSession {
+        MovieSession {
+            movie,
+            mode: Mode::Playing,
+            frame: 0,
+            event: 0,
+            desync: None,
+            start,
+        }
+    }
+
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+
+    pub fn status(&self) -> MovieStatus {
+        MovieStatus {
+            recording: self.is_recording(),
+            frame: self.frame,
+            length: self.movie.len(),
+            desync: self.desync,
+            finished: self.is_finished(),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(self.mode, Mode::Recording(_))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.mode, Mode::Finished)
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    // Host input seen since the last VBlank. Playback ignores it.
+    pub fn set_pending(&mut self, keys: JoypadState) {
+        if let Mode::Recording(pending) = &mut self.mode {
+            *pending = keys;
+        }
+    }
+
+    // A queued event reached the pad at `now`. It also stays held in the
+    // input latched at the next VBlank.
+    pub fn record_event(&mut self, now: u64, event: InputEvent) {
+        if let Mode::Recording(pending) = &mut self.mode {
+            pending.set(event.button, event.pressed);
+            self.movie.push_event(InputEvent {
+                cycle: now - self.start,
+                ..event
+            });
+        }
+    }
+
+    // When the next recorded event is due during playback.
+    pub fn next_event(&self) -> Option<u64> {
+        match self.mode {
+            Mode::Playing => self
+                .movie
+                .event(self.event)
+                .map(|event| self.start + event.cycle),
+            Mode::Recording(_) | Mode::Finished => None,
+        }
+    }
+
+    pub fn replay_events(&mut self, now: u64, keys: &mut JoypadState) {
+        while self.next_event().is_some_and(|at| at <= now) {
+            let event = self.movie.event(self.event).unwrap();
+            keys.set(event.button, event.pressed);
+            self.event += 1;
+        }
+    }
+
+    // Follows a rewind of `frames` frames back to `now`. A recording forgets
+    // what came after; playback picks up from there again.
+    pub fn rewind(&mut self, frames: usize, now: u64, keys: JoypadState) {
+        let cycle = now.saturating_sub(self.start);
+        let frame = self.frame.saturating_sub(frames);
+        self.frame = frame;
+        match &mut self.mode {
+            Mode::Recording(pending) => {
+                *pending = keys;
+                self.movie.truncate(frame, cycle);
+            }
+            Mode::Playing | Mode::Finished => {
+                self.mode = Mode::Playing;
+                self.event = self.movie.events.partition_point(|e| e.cycle <= cycle);
+            }
+        }
+    }
+
+    // Returns the input for the frame that starts now, or None once
+    // playback has run out.
+    pub fn latch(&mut self) -> Option<JoypadState> {
+        let keys = match self.mode {
+            Mode::Recording(keys) => {
+                self.movie.push(keys);
+                keys
+            }
+            Mode::Playing => match self.movie.frame(self.frame) {
+                Some(keys) => keys,
+                None => {
+                    self.mode = Mode::Finished;
+                    return None;
+                }
+            },
+            Mode::Finished => return None,
+        };
+        self.frame += 1;
+        Some(keys)
+    }
+
+    // Whether the state should be hashed for the frame just latched.
+    pub fn wants_hash(&self) -> bool {
+        (self.frame - 1).is_multiple_of(HASH_INTERVAL)
+    }
+
+    pub fn check_hash(&mut self, hash: u32) {
+        let frame = self.frame - 1;
+        match self.mode {
+            Mode::Recording(_) => self.movie.push_hash(frame, hash),
+            Mode::Playing | Mode::Finished => {
+                if self.desync.is_none() && self.movie.hash_at(frame).is_some_and(|h| h != hash) {
+                    self.desync = Some(frame);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut movie = Movie::new(0x1234_5678, vec![1, 2, 3]);
+        let mut keys = JoypadState::new();
+        movie.push(keys);
+        keys.press(Button::Start);
+        movie.push(keys);
+        movie.push_event(InputEvent {
+            cycle: 1000,
+            button: Button::Select,
+            pressed: true,
+        });
+        movie.push_hash(0, 0xDEAD_BEEF);
+
+        let data = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&data), Ok(movie));
+        assert_eq!(
+            Movie::from_bytes(&data[..data.len() - 1]),
+            Err(StateError::Truncated)
+        );
+    }
+}
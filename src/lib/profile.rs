@@ -0,0 +1,256 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{cmp::Reverse, fmt};
+
+use crate::debug::{Frame, Location};
+use crate::symbols::Symbols;
+
+const ROOT: &str = "(root)";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grouping {
+    // By PC alone, adding up every bank.
+    Pc,
+    // By PC and the ROM bank mapped there.
+    BankedPc,
+    // By the nearest label at or before PC.
+    Symbol,
+    // By the routine last called, from the shadow call stack.
+    Call,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileEntry {
+    pub name: String,
+    pub cycles: u64,
+    pub percent: f32,
+}
+
+impl fmt::Display for ProfileEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>12} {:6.2}% {}", self.cycles, self.percent, self.name)
+    }
+}
+
+// T-cycles spent per instruction address and per call stack.
+pub struct Profiler {
+    pcs: BTreeMap<Location, u64>,
+    // By the targets of the frames on the call stack, outermost first.
+    stacks: BTreeMap<Vec<Location>, u64>,
+    // The instruction being run and its call stack. Cycles for the stack are
+    // added up here until it changes.
+    at: Location,
+    path: Vec<Location>,
+    pending: u64,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            pcs: BTreeMap::new(),
+            stacks: BTreeMap::new(),
+            at: Location {
+                bank: 0,
+                address: 0,
+            },
+            path: Vec::new(),
+            pending: 0,
+            total: 0,
+        }
+    }
+
+    // Called before the CPU runs the instruction at `at`, with the call
+    // stack as it is then.
+    pub fn begin(&mut self, at: Location, frames: &[Frame]) {
+        self.at = at;
+        if !self
+            .path
+            .iter()
+            .eq(frames.iter().map(|frame| &frame.target))
+        {
+            self.flush();
+            self.path.clear();
+            self.path.extend(frames.iter().map(|frame| frame.target));
+        }
+    }
+
+    pub fn add(&mut self, cycles: u64) {
+        *self.pcs.entry(self.at).or_insert(0) += cycles;
+        self.pending += cycles;
+        self.total += cycles;
+    }
+
+    fn flush(&mut self) {
+        if self.pending != 0 {
+            *self.stacks.entry(self.path.clone()).or_insert(0) += self.pending;
+            self.pending = 0;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // Starts over, as if nothing had run yet.
+    pub fn clear(&mut self) {
+        *self = Profiler::new();
+    }
+
+    fn stacks(&self) -> impl Iterator<Item = (&Vec<Location>, u64)> {
+        let pending = (self.pending != 0).then_some((&self.path, self.pending));
+        self.stacks
+            .iter()
+            .map(|(path, cycles)| (path, *cycles))
+            .chain(pending)
+    }
+
+    // Most expensive first.
+    pub fn report(&self, grouping: Grouping, symbols: &Symbols) -> Vec<ProfileEntry> {
+        let mut groups: BTreeMap<String, u64> = BTreeMap::new();
+        let mut add = |name: String, cycles| *groups.entry(name).or_insert(0) += cycles;
+        match grouping {
+            Grouping::Pc => {
+                for (at, cycles) in &self.pcs {
+                    add(format!("{:04X}", at.address), *cycles);
+                }
+            }
+            Grouping::BankedPc => {
+                for (at, cycles) in &self.pcs {
+                    add(at.to_string(), *cycles);
+                }
+            }
+            Grouping::Symbol => {
+                for (at, cycles) in &self.pcs {
+                    let name = symbols
+                        .nearest(at.bank, at.address)
+                        .map_or("(no symbol)", |(name, _)| name);
+                    add(name.to_string(), *cycles);
+                }
+            }
+            Grouping::Call => {
+                for (path, cycles) in self.stacks() {
+                    add(name(path.last(), symbols), cycles);
+                }
+            }
+        }
+        let mut entries: Vec<ProfileEntry> = groups
+            .into_iter()
+            .map(|(name, cycles)| ProfileEntry {
+                name,
+                cycles,
+                percent: match self.total {
+                    0 => 0.0,
+                    total => cycles as f32 * 100.0 / total as f32,
+                },
+            })
+            .collect();
+        entries.sort_by_key(|entry| Reverse(entry.cycles));
+        entries
+    }
+
+    // One `outer;inner cycles` line per call stack, as read by flamegraph.pl
+    // and similar tools.
+    pub fn collapsed(&self, symbols: &Symbols) -> String {
+        let mut lines: BTreeMap<String, u64> = BTreeMap::new();
+        for (path, cycles) in self.stacks() {
+            let mut line = String::from(ROOT);
+            for target in path {
+                line.push(';');
+                line.push_str(&name(Some(target), symbols));
+            }
+            *lines.entry(line).or_insert(0) += cycles;
+        }
+        lines
+            .iter()
+            .map(|(line, cycles)| format!("{} {}\n", line, cycles))
+            .collect()
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+fn name(target: Option<&Location>, symbols: &Symbols) -> String {
+    match target {
+        Some(target) => symbols.describe(target.bank, target.address),
+        None => ROOT.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::FrameKind;
+
+    fn at(bank: u16, address: u16) -> Location {
+        Location { bank, address }
+    }
+
+    fn call(target: Location) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            site: at(0, 0x0150),
+            target,
+            return_address: 0x0153,
+            sp: 0xFFFC,
+        }
+    }
+
+    #[test]
+    fn groups_and_collapses() {
+        let symbols = Symbols::parse("00:0150 Main\n02:4000 Draw\n");
+        let draw = [call(at(2, 0x4000))];
+        let mut profiler = Profiler::new();
+        profiler.begin(at(0, 0x0150), &[]);
+        profiler.add(12);
+        profiler.begin(at(2, 0x4000), &draw);
+        profiler.add(16);
+        profiler.begin(at(3, 0x4000), &draw);
+        profiler.add(8);
+        profiler.begin(at(0, 0x0153), &[]);
+        profiler.add(4);
+        assert_eq!(profiler.total(), 40);
+
+        let names = |grouping| -> Vec<(String, u64)> {
+            profiler
+                .report(grouping, &symbols)
+                .into_iter()
+                .map(|entry| (entry.name, entry.cycles))
+                .collect()
+        };
+        let entry = |name: &str, cycles| (name.to_string(), cycles);
+        assert_eq!(
+            names(Grouping::Pc),
+            [entry("4000", 24), entry("0150", 12), entry("0153", 4)]
+        );
+        assert_eq!(names(Grouping::BankedPc)[0], entry("02:4000", 16));
+        assert_eq!(
+            names(Grouping::Symbol),
+            [
+                entry("Draw", 16),
+                entry("Main", 16),
+                entry("(no symbol)", 8)
+            ]
+        );
+        assert_eq!(
+            names(Grouping::Call),
+            [entry("Draw", 24), entry("(root)", 16)]
+        );
+        assert_eq!(profiler.collapsed(&symbols), "(root) 16\n(root);Draw 24\n");
+
+        // After a clear, cycles are not charged to the stack in use before it.
+        profiler.begin(at(2, 0x4000), &draw);
+        profiler.clear();
+        profiler.add(4);
+        assert_eq!(profiler.total(), 4);
+        assert_eq!(profiler.collapsed(&symbols), "(root) 4\n");
+    }
+}
@@ -0,0 +1,219 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec,
+    vec::Vec,
+};
+
+// One recorded bus access, with where the PPU and CPU were when it happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    // Frames completed so far. It goes up at the start of vblank, so the
+    // accesses made during vblank count towards the frame drawn after it.
+    pub frame: u64,
+    pub ly: u8,
+    // Dot within the line, 0-455. Always 0 while the LCD is off.
+    pub dot: u16,
+    // The instruction that made the access. For the pushes of an interrupt
+    // dispatch, the one that was interrupted.
+    pub pc: u16,
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccessCount {
+    pub reads: u32,
+    pub writes: u32,
+}
+
+// Accesses per address over one frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heatmap {
+    pub frame: u64,
+    counts: BTreeMap<u16, AccessCount>,
+}
+
+impl Heatmap {
+    pub fn get(&self, address: u16) -> AccessCount {
+        self.counts.get(&address).copied().unwrap_or_default()
+    }
+
+    // Addresses that were accessed, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, AccessCount)> + '_ {
+        self.counts
+            .iter()
+            .map(|(address, count)| (*address, *count))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecorderConfig {
+    // Inclusive address ranges to record.
+    pub ranges: Vec<(u16, u16)>,
+    // Record reads as well as writes.
+    pub reads: bool,
+    // Accesses and frame heatmaps held until the host takes them. Past
+    // these, the oldest are dropped.
+    pub capacity: usize,
+    pub frames: usize,
+}
+
+impl Default for RecorderConfig {
+    // Writes to VRAM, OAM and I/O registers.
+    fn default() -> RecorderConfig {
+        RecorderConfig {
+            ranges: vec![(0x8000, 0x9FFF), (0xFE00, 0xFE9F), (0xFF00, 0xFF7F)],
+            reads: false,
+            capacity: 0x10000,
+            frames: 60,
+        }
+    }
+}
+
+// Only accesses made by the CPU while running an instruction are recorded;
+// DMA and the emulator's own accesses are not.
+pub struct AccessRecorder {
+    config: RecorderConfig,
+    accesses: VecDeque<Access>,
+    // Oldest first. Frames without any recorded access are left out.
+    heatmaps: VecDeque<Heatmap>,
+    dropped: u64,
+}
+
+impl AccessRecorder {
+    pub fn new(config: RecorderConfig) -> AccessRecorder {
+        AccessRecorder {
+            config,
+            accesses: VecDeque::new(),
+            heatmaps: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn wants(&self, address: u16, write: bool) -> bool {
+        (write || self.config.reads)
+            && self
+                .config
+                .ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&address))
+    }
+
+    pub fn record(&mut self, access: Access) {
+        if self
+            .heatmaps
+            .back()
+            .is_none_or(|map| map.frame != access.frame)
+        {
+            self.heatmaps.push_back(Heatmap {
+                frame: access.frame,
+                counts: BTreeMap::new(),
+            });
+            if self.heatmaps.len() > self.config.frames {
+                // Accesses never outlive the heatmap of their frame.
+                let frame = self.heatmaps.pop_front().unwrap().frame;
+                while self
+                    .accesses
+                    .front()
+                    .is_some_and(|access| access.frame == frame)
+                {
+                    self.accesses.pop_front();
+                    self.dropped += 1;
+                }
+            }
+        }
+        if let Some(map) = self.heatmaps.back_mut() {
+            let count = map.counts.entry(access.address).or_default();
+            match access.write {
+                true => count.writes += 1,
+                false => count.reads += 1,
+            }
+        }
+        if self.accesses.len() >= self.config.capacity {
+            self.dropped += 1;
+            if self.accesses.pop_front().is_none() {
+                return;
+            }
+        }
+        self.accesses.push_back(access);
+    }
+
+    pub fn accesses(&self) -> impl Iterator<Item = &Access> + '_ {
+        self.accesses.iter()
+    }
+
+    pub fn heatmaps(&self) -> impl Iterator<Item = &Heatmap> + '_ {
+        self.heatmaps.iter()
+    }
+
+    // Accesses that were dropped to stay within the capacity.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        self.accesses.drain(..).collect()
+    }
+
+    pub fn take_heatmaps(&mut self) -> Vec<Heatmap> {
+        self.heatmaps.drain(..).collect()
+    }
+
+    // Takes the heatmap of the oldest frame before `current`, along with
+    // the accesses still held for it.
+    pub fn take_frame(&mut self, current: u64) -> Option<(Heatmap, Vec<Access>)> {
+        if self.heatmaps.front()?.frame >= current {
+            return None;
+        }
+        let heatmap = self.heatmaps.pop_front()?;
+        let len = self
+            .accesses
+            .iter()
+            .take_while(|access| access.frame == heatmap.frame)
+            .count();
+        Some((heatmap, self.accesses.drain(..len).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(frame: u64, address: u16) -> Access {
+        Access {
+            frame,
+            ly: 0,
+            dot: 0,
+            pc: 0x0150,
+            address,
+            value: 0,
+            write: true,
+        }
+    }
+
+    #[test]
+    fn drops_oldest_and_drains_by_frame() {
+        let mut recorder = AccessRecorder::new(RecorderConfig {
+            capacity: 3,
+            frames: 2,
+            ..RecorderConfig::default()
+        });
+        for (frame, address) in [(0, 0x8000), (1, 0x8001), (1, 0x8002), (2, 0x8003)] {
+            recorder.record(write(frame, address));
+        }
+        // Frame 0 fell out of both the heatmaps and the accesses.
+        assert_eq!(recorder.dropped(), 1);
+        assert_eq!(recorder.heatmaps().count(), 2);
+        assert_eq!(recorder.accesses().count(), 3);
+
+        assert_eq!(recorder.take_frame(1), None);
+        let (heatmap, accesses) = recorder.take_frame(2).unwrap();
+        assert_eq!(heatmap.frame, 1);
+        assert_eq!(heatmap.get(0x8002).writes, 1);
+        assert_eq!(accesses, [write(1, 0x8001), write(1, 0x8002)]);
+        assert_eq!(recorder.take_frame(2), None);
+        assert_eq!(recorder.take_frame(3).unwrap().1, [write(2, 0x8003)]);
+        assert_eq!(recorder.accesses().count(), 0);
+    }
+}
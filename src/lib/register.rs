@@ -1,3 +1,5 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
 pub enum Flag {
     Z = 0x80,
     S = 0x40,
@@ -84,6 +86,32 @@ impl Registers {
     }
 }
 
+impl SaveState for Registers {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&[
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ]);
+        w.u16(self.sp);
+        w.u16(self.pc);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut regs = [0; 8];
+        r.bytes(&mut regs)?;
+        let [a, f, b, c, d, e, h, l] = regs;
+        self.a = a;
+        self.f = f & 0xF0;
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,220 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::input::JoypadState;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RewindConfig {
+    // Frames between two snapshots.
+    pub interval: u32,
+    // Number of snapshots kept before the oldest one is dropped.
+    pub capacity: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> RewindConfig {
+        RewindConfig {
+            interval: 30,
+            capacity: 120,
+        }
+    }
+}
+
+struct Snapshot {
+    frame: u64,
+    cycles: u64,
+    // This snapshot XOR the one before it, run-length encoded. Only the
+    // newest state is kept in full; older ones are rebuilt backwards.
+    delta: Vec<u8>,
+}
+
+pub struct Rewind {
+    config: RewindConfig,
+    snapshots: VecDeque<Snapshot>,
+    newest: Vec<u8>,
+    inputs: Vec<(u64, JoypadState)>,
+    replaying: bool,
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Rewind {
+        Rewind {
+            config: RewindConfig {
+                interval: config.interval.max(1),
+                capacity: config.capacity.max(1),
+            },
+            snapshots: VecDeque::new(),
+            newest: Vec::new(),
+            inputs: Vec::new(),
+            replaying: false,
+        }
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replaying
+    }
+
+    pub fn record_keys(&mut self, cycles: u64, keys: JoypadState) {
+        match self.inputs.last() {
+            Some((_, last)) if *last == keys => {}
+            _ => self.inputs.push((cycles, keys)),
+        }
+    }
+
+    // Input changes recorded after `cycles`, to be fed back during replay.
+    pub fn keys_after(&self, cycles: u64) -> &[(u64, JoypadState)] {
+        let idx = self.inputs.partition_point(|(at, _)| *at <= cycles);
+        &self.inputs[idx..]
+    }
+
+    pub fn record_frame(&mut self, frame: u64, cycles: u64, state: Vec<u8>) {
+        if !self.snapshots.is_empty() && !frame.is_multiple_of(self.config.interval as u64) {
+            return;
+        }
+        let delta = encode_delta(&state, &self.newest);
+        self.newest = state;
+        self.snapshots.push_back(Snapshot {
+            frame,
+            cycles,
+            delta,
+        });
+        if self.snapshots.len() > self.config.capacity {
+            self.snapshots.pop_front();
+            self.trim_inputs();
+        }
+    }
+
+    // Rebuilds the newest snapshot taken at or before `frame`, or the oldest
+    // one if nothing that old is left, dropping every snapshot after it.
+    pub fn restore(&mut self, frame: u64) -> Option<Vec<u8>> {
+        let frame = frame.max(self.snapshots.front()?.frame);
+        let mut state = core::mem::take(&mut self.newest);
+        while self.snapshots.back().is_some_and(|s| s.frame > frame) {
+            let snapshot = self.snapshots.pop_back().unwrap();
+            state = apply_delta(&state, &snapshot.delta);
+        }
+        self.newest = state.clone();
+        Some(state)
+    }
+
+    pub fn start_replay(&mut self) {
+        self.replaying = true;
+    }
+
+    pub fn finish_replay(&mut self, cycles: u64) {
+        self.replaying = false;
+        let keep = self.inputs.partition_point(|(at, _)| *at <= cycles);
+        self.inputs.truncate(keep);
+    }
+
+    fn trim_inputs(&mut self) {
+        // Keep the last input change before the oldest snapshot, since it is
+        // still the one in effect when replay starts from there.
+        let start = match self.snapshots.front() {
+            Some(oldest) => oldest.cycles,
+            None => return,
+        };
+        let idx = self.inputs.partition_point(|(at, _)| *at <= start);
+        if idx > 1 {
+            self.inputs.drain(..idx - 1);
+        }
+    }
+}
+
+// Delta format: repeated (zero run, literal length, literal bytes), with both
+// lengths as LEB128 varints. XOR deltas between frames are mostly zero.
+fn encode_delta(state: &[u8], base: &[u8]) -> Vec<u8> {
+    let len = state.len().max(base.len());
+    let xor = |idx: usize| -> u8 {
+        state.get(idx).copied().unwrap_or(0) ^ base.get(idx).copied().unwrap_or(0)
+    };
+    let mut out = Vec::new();
+    write_varint(&mut out, base.len());
+    let mut idx = 0;
+    while idx < len {
+        let zeros = (idx..len).take_while(|i| xor(*i) == 0).count();
+        idx += zeros;
+        let literal = (idx..len).take_while(|i| xor(*i) != 0).count();
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literal);
+        out.extend((idx..idx + literal).map(xor));
+        idx += literal;
+    }
+    out
+}
+
+fn apply_delta(state: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut out = state.to_vec();
+    out.resize(out.len().max(len), 0);
+    let mut idx = 0;
+    while pos < delta.len() {
+        idx += read_varint(delta, &mut pos);
+        let literal = read_varint(delta, &mut pos);
+        for byte in &delta[pos..pos + literal] {
+            out[idx] ^= byte;
+            idx += 1;
+        }
+        pos += literal;
+    }
+    out.truncate(len);
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn delta_round_trip() {
+        let base = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut state = base.clone();
+        state[3] = 0x33;
+        state[4] = 0x44;
+        state[9] = 0x99;
+        let delta = encode_delta(&state, &base);
+        assert!(delta.len() < state.len());
+        assert_eq!(apply_delta(&state, &delta), base);
+    }
+
+    #[test]
+    fn restore_walks_back() {
+        let mut rewind = Rewind::new(RewindConfig {
+            interval: 1,
+            capacity: 3,
+        });
+        for frame in 0..5u8 {
+            rewind.record_frame(frame as u64, frame as u64 * 100, vec![frame; 64]);
+        }
+        assert_eq!(rewind.restore(3), Some(vec![3; 64]));
+        assert_eq!(rewind.restore(10), Some(vec![3; 64]));
+        assert_eq!(rewind.restore(2), Some(vec![2; 64]));
+        // Frame 1 fell out of the buffer, so the oldest left stands in.
+        assert_eq!(rewind.restore(1), Some(vec![2; 64]));
+        assert_eq!(Rewind::new(RewindConfig::default()).restore(0), None);
+    }
+}
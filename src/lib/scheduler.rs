@@ -0,0 +1,95 @@
+use core::cell::Cell;
+
+// Events due at the same instruction boundary are handled in this order,
+// which matches the order devices used to be stepped in. Serial transfers and
+// the APU frame sequencer have no events because neither is emulated: there
+// is no serial port, and the sound unit is not wired to the bus. Host input
+// is not an event either; it is polled after every instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    // The timer raises its interrupt.
+    Timer,
+    Ppu,
+    Dma,
+    Joypad,
+}
+
+const EVENTS: [Event; 4] = [Event::Timer, Event::Ppu, Event::Dma, Event::Joypad];
+const NEVER: u64 = u64::MAX;
+
+// Cells so that device handlers, which only see `&MemoryBus`, can schedule.
+pub struct Scheduler {
+    now: Cell<u64>,
+    deadlines: [Cell<u64>; EVENTS.len()],
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            now: Cell::new(0),
+            deadlines: [const { Cell::new(NEVER) }; EVENTS.len()],
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now.get()
+    }
+
+    pub fn set_now(&self, now: u64) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, cycles: u64) {
+        self.now.set(self.now.get() + cycles);
+    }
+
+    pub fn schedule(&self, event: Event, at: u64) {
+        self.deadlines[event as usize].set(at);
+    }
+
+    pub fn cancel(&self, event: Event) {
+        self.deadlines[event as usize].set(NEVER);
+    }
+
+    pub fn deadline(&self, event: Event) -> Option<u64> {
+        match self.deadlines[event as usize].get() {
+            NEVER => None,
+            at => Some(at),
+        }
+    }
+
+    // Takes the first event that is due. The event is cleared, so the
+    // handler has to schedule it again if it should recur.
+    pub fn pop_due(&self) -> Option<Event> {
+        let now = self.now.get();
+        for event in EVENTS {
+            let deadline = &self.deadlines[event as usize];
+            if deadline.get() <= now {
+                deadline.set(NEVER);
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pop_in_priority_order() {
+        let scheduler = Scheduler::new();
+        scheduler.schedule(Event::Joypad, 4);
+        scheduler.schedule(Event::Ppu, 8);
+        scheduler.schedule(Event::Dma, 2);
+        assert_eq!(scheduler.pop_due(), None);
+
+        scheduler.advance(8);
+        assert_eq!(scheduler.pop_due(), Some(Event::Ppu));
+        assert_eq!(scheduler.pop_due(), Some(Event::Dma));
+        assert_eq!(scheduler.pop_due(), Some(Event::Joypad));
+        assert_eq!(scheduler.pop_due(), None);
+        assert_eq!(scheduler.deadline(Event::Ppu), None);
+    }
+}
@@ -0,0 +1,266 @@
+use alloc::vec::Vec;
+
+use crate::error::ErrorKind;
+
+const MAGIC: [u8; 4] = *b"RGBS";
+pub const STATE_VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    Truncated,
+    BadSection([u8; 4]),
+    Corrupted,
+    // Re-running the frames after a rewind snapshot failed.
+    Replay(ErrorKind),
+}
+
+pub trait SaveState {
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u32) -> StateWriter {
+        let mut w = StateWriter { buf: Vec::new() };
+        w.bytes(&MAGIC);
+        w.u16(STATE_VERSION);
+        w.u32(rom_hash);
+        w
+    }
+
+    // A writer without the state header, for other formats built from the
+    // same primitives.
+    pub fn raw() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    // Each component is written as a tagged, length-prefixed section so that
+    // a reader can tell exactly where one component stops and the next starts.
+    pub fn section<T: SaveState>(&mut self, tag: &[u8; 4], component: &T) {
+        self.bytes(tag);
+        let len_at = self.buf.len();
+        self.u32(0);
+        component.save(self);
+        let len = (self.buf.len() - len_at - 4) as u32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.buf.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], rom_hash: u32) -> Result<StateReader<'a>, StateError> {
+        let mut r = StateReader { data, pos: 0 };
+        let mut magic = [0; 4];
+        r.bytes(&mut magic)?;
+        if magic != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        // Only one layout has been released, so there is nothing to migrate.
+        let version = r.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if r.u32()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(r)
+    }
+
+    pub fn raw(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn section<T: SaveState>(
+        &mut self,
+        tag: &[u8; 4],
+        component: &mut T,
+    ) -> Result<(), StateError> {
+        let mut found = [0; 4];
+        self.bytes(&mut found)?;
+        if &found != tag {
+            return Err(StateError::BadSection(found));
+        }
+        let len = self.u32()? as usize;
+        let end = self.pos.checked_add(len).ok_or(StateError::Truncated)?;
+        if end > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        let mut inner = StateReader {
+            data: &self.data[..end],
+            pos: self.pos,
+        };
+        component.load(&mut inner)?;
+        if inner.pos != end {
+            return Err(StateError::BadSection(*tag));
+        }
+        self.pos = end;
+        Ok(())
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(StateError::Corrupted)
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupted),
+        }
+    }
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+}
+
+pub fn hash(data: &[u8]) -> u32 {
+    // FNV-1a, used to tie a state to the ROM it was taken from.
+    let mut hash: u32 = 0x811C_9DC5;
+    for byte in data {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+// 64-bit FNV-1a, for comparing whole states.
+pub fn hash64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Pair(u8, u16);
+
+    impl SaveState for Pair {
+        fn save(&self, w: &mut StateWriter) {
+            w.u8(self.0);
+            w.u16(self.1);
+        }
+        fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+            self.0 = r.u8()?;
+            self.1 = r.u16()?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn section_round_trip() {
+        let mut w = StateWriter::new(0x1234);
+        w.section(b"PAIR", &Pair(0x42, 0xBEEF));
+        let data = w.finish();
+
+        let mut pair = Pair(0, 0);
+        let mut r = StateReader::new(&data, 0x1234).unwrap();
+        r.section(b"PAIR", &mut pair).unwrap();
+        r.finish().unwrap();
+        assert_eq!(pair.0, 0x42);
+        assert_eq!(pair.1, 0xBEEF);
+    }
+
+    #[test]
+    fn reject_bad_header() {
+        let mut data = StateWriter::new(0x1234).finish();
+        assert_eq!(
+            StateReader::new(&data, 0x4321).err(),
+            Some(StateError::RomMismatch)
+        );
+        data[4] = 0xFF;
+        assert_eq!(
+            StateReader::new(&data, 0x1234).err(),
+            Some(StateError::UnsupportedVersion(0x00FF))
+        );
+        data[0] = b'X';
+        assert_eq!(
+            StateReader::new(&data, 0x1234).err(),
+            Some(StateError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn reject_short_section() {
+        let mut w = StateWriter::new(0);
+        w.section(b"PAIR", &Pair(1, 2));
+        let data = w.finish();
+        let mut r = StateReader::new(&data[..data.len() - 1], 0).unwrap();
+        assert_eq!(
+            r.section(b"PAIR", &mut Pair(0, 0)),
+            Err(StateError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+// Start of each memory region. A label is only used for addresses after it
+// in the same region.
+const REGIONS: [u16; 9] = [
+    0x0000, 0x4000, 0x8000, 0xA000, 0xC000, 0xE000, 0xFE00, 0xFF00, 0xFF80,
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub bank: u16,
+    pub address: u16,
+    pub name: String,
+}
+
+// Labels from an RGBDS or no$gmb .sym file.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    // Sorted by `key`.
+    symbols: Vec<Symbol>,
+}
+
+// Only switchable ROM is banked on DMG, so the bank of anything else is
+// ignored when matching.
+fn key(bank: u16, address: u16) -> (u16, u16) {
+    match address {
+        0x4000..=0x7FFF => (bank, address),
+        _ => (0, address),
+    }
+}
+
+fn region(address: u16) -> u16 {
+    REGIONS
+        .iter()
+        .rev()
+        .find(|start| address >= **start)
+        .copied()
+        .unwrap()
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    // Lines look like `01:4A3F Label`; blank lines, `;` comments and lines
+    // that do not parse are skipped.
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap().trim();
+            let parsed = line.split_once(char::is_whitespace).and_then(|(at, name)| {
+                let (bank, address) = at.split_once(':')?;
+                Some((
+                    u16::from_str_radix(bank, 16).ok()?,
+                    u16::from_str_radix(address, 16).ok()?,
+                    name.trim(),
+                ))
+            });
+            if let Some((bank, address, name)) = parsed {
+                symbols.add(bank, address, name);
+            }
+        }
+        symbols
+    }
+
+    pub fn add(&mut self, bank: u16, address: u16, name: &str) {
+        let symbol = Symbol {
+            bank,
+            address,
+            name: name.to_string(),
+        };
+        let at = key(bank, address);
+        // Later labels at the same address go after earlier ones, so the
+        // first one in the file is the one shown.
+        let idx = self
+            .symbols
+            .partition_point(|s| key(s.bank, s.address) <= at);
+        self.symbols.insert(idx, symbol);
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // The label at exactly `address`, with `bank` mapped.
+    pub fn label(&self, bank: u16, address: u16) -> Option<&str> {
+        let at = key(bank, address);
+        let idx = self
+            .symbols
+            .partition_point(|s| key(s.bank, s.address) < at);
+        self.symbols
+            .get(idx)
+            .filter(|s| key(s.bank, s.address) == at)
+            .map(|s| s.name.as_str())
+    }
+
+    // The closest label at or before `address` in the same region, and how
+    // far past it `address` is.
+    pub fn nearest(&self, bank: u16, address: u16) -> Option<(&str, u16)> {
+        let at = key(bank, address);
+        let idx = self
+            .symbols
+            .partition_point(|s| key(s.bank, s.address) <= at);
+        let last = self.symbols[..idx].last()?;
+        let found = key(last.bank, last.address);
+        if found.0 != at.0 || region(found.1) != region(address) {
+            return None;
+        }
+        // The first of several labels at the same address.
+        let first = &self.symbols[self
+            .symbols
+            .partition_point(|s| key(s.bank, s.address) < found)];
+        Some((first.name.as_str(), address - found.1))
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    // `Label`, `Label+$3`, or `bank:address` when there is no label.
+    pub fn describe(&self, bank: u16, address: u16) -> String {
+        match self.nearest(bank, address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+${:X}", name, offset),
+            None => format!("{:02X}:{:04X}", key(bank, address).0, address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink
+00:0150 Start
+00:0150 Entry
+00:0158 Start.loop
+01:4000 BankOne
+02:4000 BankTwo
+00:C000 wCounter
+
+not a symbol
+";
+
+    #[test]
+    fn bank_aware_lookup() {
+        let symbols = Symbols::parse(SYM);
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols.label(5, 0x0150), Some("Start"));
+        assert_eq!(symbols.label(2, 0x4000), Some("BankTwo"));
+        assert_eq!(symbols.label(3, 0x4000), None);
+        assert_eq!(symbols.describe(0, 0x015A), "Start.loop+$2");
+        assert_eq!(symbols.describe(1, 0x4A3F), "BankOne+$A3F");
+        assert_eq!(symbols.describe(3, 0x4A3F), "03:4A3F");
+        // wCounter is in WRAM, so it does not reach into echo RAM.
+        assert_eq!(symbols.describe(0, 0xC010), "wCounter+$10");
+        assert_eq!(symbols.describe(0, 0xE000), "00:E000");
+        assert_eq!(symbols.find("BankTwo").map(|s| s.bank), Some(2));
+    }
+}
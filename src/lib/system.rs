@@ -1,15 +1,58 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+
 use crate::{
-    cpu::CPU,
+    cdl::CodeDataLog,
+    cpu::{CpuState, CPU},
     cycle::Clock,
+    debug::{
+        Breakpoint, Debugger, Frame, Location, StackWarning, TraceEntry, WatchHit, Watchpoint,
+    },
     device::Device,
+    disasm::{self, Disassembly},
     dma::DMA,
+    error::{EmuError, ErrorKind},
     gpu::{FrameBuffer, GPU},
     hardware::{Hardware, HardwareHandle},
-    input::Pad,
+    input::{Button, InputEvent, JoypadState, Pad},
     mbc::Cartridge,
     mmu::MemoryBus,
+    movie::{Movie, MovieSession, MovieStatus},
+    profile::Profiler,
+    recorder::{Access, AccessRecorder, Heatmap, RecorderConfig},
+    rewind::{Rewind, RewindConfig},
+    scheduler::Event,
+    state::{self, StateError, StateReader, StateWriter},
+    symbols::Symbols,
 };
 
+// One frame of the LCD, in CPU cycles.
+pub const FRAME_CYCLES: u64 = 70224;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakReason {
+    // The PPU entered VBlank.
+    Frame,
+    // The requested number of cycles has run.
+    CycleBudget,
+    // The LCD is off, so no VBlank came within a frame's worth of cycles.
+    LcdOff,
+    // The CPU is in STOP mode and waiting for a button press.
+    Stopped,
+    // The CPU is about to run the instruction at this breakpoint.
+    Breakpoint(u16),
+    // The last instruction made a watched access.
+    Watchpoint(WatchHit),
+    // A step, step-over, step-out or run-until-return finished.
+    Step,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunSummary {
+    pub frames: u64,
+    pub cycles: u64,
+    pub reason: BreakReason,
+}
+
 pub struct System {
     cpu: CPU,
     bus: MemoryBus,
@@ -19,6 +62,11 @@ pub struct System {
     clock: Device<Clock>,
     input: Device<Pad>,
     hardware: HardwareHandle,
+    rewind: Option<Rewind>,
+    movie: Option<MovieSession>,
+    debugger: Debugger,
+    symbols: Symbols,
+    profiler: Option<Profiler>,
 }
 
 impl System {
@@ -38,6 +86,7 @@ impl System {
         let input = Device::new(Pad::new());
 
         bus.add_handler((0x0000, 0x7FFF), cartridge.handler());
+        bus.map_rom(cartridge.borrow().rom());
         bus.add_handler((0x8000, 0x9FFF), gpu.handler());
         bus.add_handler((0xA000, 0xBFFF), cartridge.handler());
         bus.add_handler((0xFE00, 0xFE9F), gpu.handler());
@@ -48,6 +97,12 @@ impl System {
         bus.add_handler((0xFF46, 0xFF46), dma.handler());
         bus.add_handler((0xFF47, 0xFF4B), gpu.handler());
 
+        gpu.borrow_mut().resync(bus.scheduler());
+        let line = gpu.clone();
+        bus.set_line_source(Some(Box::new(move |scheduler| {
+            line.borrow_mut().ly(scheduler)
+        })));
+
         System {
             cpu: cpu,
             bus: bus,
@@ -57,33 +112,1578 @@ impl System {
             clock: clock,
             input: input,
             hardware: hardware,
+            rewind: None,
+            movie: None,
+            debugger: Debugger::new(),
+            symbols: Symbols::new(),
+            profiler: None,
         }
     }
 
-    pub fn step(&mut self) -> u32 {
-        let elasped_cycle = self.cpu.step(&mut self.bus);
-        self.clock.borrow_mut().step(&mut self.bus, elasped_cycle);
-        self.gpu
-            .borrow_mut()
-            .step(elasped_cycle, &mut self.bus, &self.hardware);
-        self.dma.borrow_mut().step(&mut self.bus);
-        self.input.borrow_mut().step(&self.hardware);
-        self.hardware.get().borrow_mut().update();
-        elasped_cycle as u32
+    // On error nothing past the failing instruction is run, so the System can
+    // still be inspected or saved by the host.
+    pub fn step(&mut self) -> Result<u32, EmuError> {
+        let stopped = self.cpu.is_stopped();
+        // An interrupt dispatch is charged to the handler it enters, once its
+        // frame is pushed, not to the instruction that was interrupted.
+        let dispatch = self.profiler.is_some() && self.cpu.interrupt_due(&self.bus);
+        if let Some(profiler) = self.profiler.as_mut().filter(|_| !dispatch) {
+            profiler.begin(self.bus.location(self.cpu.pc()), self.cpu.calls().frames());
+        }
+        let elasped_cycle = self.cpu.step(&mut self.bus)?;
+        if let Some(profiler) = &mut self.profiler {
+            if dispatch {
+                profiler.begin(self.bus.location(self.cpu.pc()), self.cpu.calls().frames());
+            }
+            profiler.add(elasped_cycle as u64);
+        }
+        if stopped {
+            // The timer is frozen in STOP mode.
+            self.clock.borrow_mut().resync(self.bus.scheduler());
+        } else if self.cpu.is_stopped() {
+            // Count the STOP instruction itself before the timer freezes.
+            self.timer_event();
+            self.gpu
+                .borrow_mut()
+                .set_stopped(true, self.bus.scheduler());
+        }
+        while let Some(event) = self.bus.scheduler().pop_due() {
+            match event {
+                Event::Timer => self.timer_event(),
+                Event::Ppu => self.ppu_event(),
+                Event::Dma => self.dma.borrow_mut().step(&mut self.bus),
+                Event::Joypad => self.joypad_event(),
+            }
+            if let Some(kind) = self.bus.take_fault() {
+                return Err(self.cpu.error(kind, self.cpu.pc()));
+            }
+        }
+        self.poll_input();
+        Ok(elasped_cycle as u32)
+    }
+
+    fn timer_event(&mut self) {
+        let mut clock = self.clock.borrow_mut();
+        clock.sync(self.bus.scheduler());
+        if clock.take_interrupt() {
+            self.bus.set_if(self.bus.get_if() | 0x04);
+        }
+        clock.schedule(self.bus.scheduler());
+    }
+
+    fn ppu_event(&mut self) {
+        let vblank = {
+            let mut gpu = self.gpu.borrow_mut();
+            gpu.sync(self.bus.scheduler());
+            gpu.take_vblank()
+        };
+        if !vblank {
+            return;
+        }
+        self.bus.set_if(self.bus.get_if() | 0x01);
+        self.bus.set_frame(self.frames());
+        if !self.is_replaying() {
+            self.latch_movie_frame();
+        }
+        if let Some(mut rewind) = self.rewind.take() {
+            if !rewind.is_replaying() {
+                rewind.record_frame(self.frames(), self.cycles(), self.save_state());
+            }
+            self.rewind = Some(rewind);
+        }
+        if !self.is_replaying() {
+            let gpu = self.gpu.borrow();
+            self.hardware
+                .get()
+                .borrow_mut()
+                .draw_framebuffer(gpu.frame_buffer());
+        }
+    }
+
+    // The host is polled after every instruction, as the pad used to be
+    // stepped with every other device.
+    fn poll_input(&mut self) {
+        if !self.is_replaying() {
+            let mut hardware = self.hardware.get().borrow_mut();
+            if let Some(keys) = hardware.get_keys() {
+                match &mut self.movie {
+                    Some(session) if !session.is_finished() => session.set_pending(keys),
+                    _ => self.input.borrow_mut().set_state(keys),
+                }
+            }
+            hardware.update();
+        }
+        self.joypad_changed();
+    }
+
+    // Queued events reach the pad at their cycle, movie or not. A rewind
+    // replay leaves the movie alone.
+    fn joypad_event(&mut self) {
+        let now = self.bus.scheduler().now();
+        let replaying = self.is_replaying();
+        {
+            let mut pad = self.input.borrow_mut();
+            match self.movie.as_mut().filter(|_| !replaying) {
+                Some(session) if session.is_recording() => {
+                    while let Some(event) = pad.pop_event(now) {
+                        let mut keys = pad.state();
+                        keys.set(event.button, event.pressed);
+                        pad.set_state(keys);
+                        session.record_event(now, event);
+                    }
+                }
+                Some(session) if !session.is_finished() => {
+                    // Playback ignores input from the host.
+                    pad.drain_events(now, &mut JoypadState::new());
+                    let mut keys = pad.state();
+                    session.replay_events(now, &mut keys);
+                    pad.set_state(keys);
+                }
+                _ => pad.apply_events(now),
+            }
+        }
+        self.schedule_joypad();
+        self.joypad_changed();
+    }
+
+    fn schedule_joypad(&mut self) {
+        let pad = self.input.borrow().next_event();
+        let movie = match &self.movie {
+            Some(session) if !self.is_replaying() => session.next_event(),
+            _ => None,
+        };
+        let scheduler = self.bus.scheduler();
+        match pad.into_iter().chain(movie).min() {
+            Some(at) => scheduler.schedule(Event::Joypad, at.max(scheduler.now())),
+            None => scheduler.cancel(Event::Joypad),
+        }
+    }
+
+    fn joypad_changed(&mut self) {
+        let keys = {
+            let mut pad = self.input.borrow_mut();
+            if pad.take_interrupt() {
+                self.bus.set_if(self.bus.get_if() | 0x10);
+            }
+            pad.state()
+        };
+        let now = self.cycles();
+        if let Some(rewind) = &mut self.rewind {
+            if !rewind.is_replaying() {
+                rewind.record_keys(now, keys);
+            }
+        }
+        // Any selected joypad line going low ends STOP mode.
+        if self.cpu.is_stopped() && self.bus.peek(0xFF00) & 0x0F != 0x0F {
+            self.cpu.wake();
+            self.gpu
+                .borrow_mut()
+                .set_stopped(false, self.bus.scheduler());
+        }
+    }
+
+    // While a movie is active, host input only reaches the pad at VBlank, so
+    // a recording holds everything the game could have seen.
+    fn latch_movie_frame(&mut self) {
+        let mut session = match self.movie.take() {
+            Some(session) => session,
+            None => return,
+        };
+        if let Some(keys) = session.latch() {
+            self.input.borrow_mut().set_state(keys);
+            if session.wants_hash() {
+                session.check_hash(state::hash(&self.save_state()));
+            }
+        }
+        self.movie = Some(session);
+        self.joypad_changed();
+    }
+
+    // Records from the current state on. Input from the host is latched once
+    // per frame until the recording is stopped.
+    pub fn start_recording(&mut self) {
+        let movie = Movie::new(self.cartrigde.borrow().rom_hash(), self.save_state());
+        let keys = self.input.borrow().state();
+        self.movie = Some(MovieSession::record(movie, keys, self.cycles()));
+    }
+
+    // Loads the movie's start state and replaces host input with the
+    // recording.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), StateError> {
+        if movie.rom_hash() != self.cartrigde.borrow().rom_hash() {
+            return Err(StateError::RomMismatch);
+        }
+        self.load_state(movie.start())?;
+        self.movie = Some(MovieSession::play(movie, self.cycles()));
+        self.schedule_joypad();
+        Ok(())
+    }
+
+    // Ends recording or playback, handing back the movie.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(MovieSession::into_movie)
+    }
+
+    pub fn movie_status(&self) -> Option<MovieStatus> {
+        self.movie.as_ref().map(MovieSession::status)
+    }
+
+    // Presses or releases a button at `event.cycle`, or at the next
+    // instruction boundary if that has already passed.
+    pub fn queue_input(&mut self, event: InputEvent) {
+        self.input.borrow_mut().queue(event);
+        self.schedule_joypad();
+    }
+
+    // Runs until the next VBlank, or for one frame's worth of cycles while
+    // the LCD is off or the CPU is stopped. Breakpoints, watchpoints and a
+    // pending step end the run early.
+    pub fn run_frame(&mut self) -> Result<RunSummary, EmuError> {
+        let start_frames = self.frames();
+        let start_cycles = self.cycles();
+        let mut first = true;
+        loop {
+            if let Some(reason) = self.debug_step(first)? {
+                let cycles = self.cycles() - start_cycles;
+                return Ok(self.summary(start_frames, cycles, reason));
+            }
+            first = false;
+            let cycles = self.cycles() - start_cycles;
+            if self.frames() != start_frames {
+                return Ok(self.summary(start_frames, cycles, BreakReason::Frame));
+            }
+            if cycles >= FRAME_CYCLES && self.cpu.is_stopped() {
+                return Ok(self.summary(start_frames, cycles, BreakReason::Stopped));
+            }
+            if cycles >= FRAME_CYCLES && !self.gpu.borrow().lcd_enabled() {
+                return Ok(self.summary(start_frames, cycles, BreakReason::LcdOff));
+            }
+        }
+    }
+
+    // Runs at least `cycles` cycles; the last instruction may overshoot.
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<RunSummary, EmuError> {
+        let start_frames = self.frames();
+        let start_cycles = self.cycles();
+        let mut first = true;
+        while self.cycles() - start_cycles < cycles {
+            if let Some(reason) = self.debug_step(first)? {
+                let cycles = self.cycles() - start_cycles;
+                return Ok(self.summary(start_frames, cycles, reason));
+            }
+            first = false;
+        }
+        Ok(self.summary(
+            start_frames,
+            self.cycles() - start_cycles,
+            BreakReason::CycleBudget,
+        ))
+    }
+
+    // One step, with breakpoints, watchpoints and step targets checked when
+    // any are set. Breakpoints are skipped on the first step of a run so that
+    // running again moves past the one that ended the last run.
+    fn debug_step(&mut self, first: bool) -> Result<Option<BreakReason>, EmuError> {
+        if !self.debugger.is_active() && !self.bus.is_watching() {
+            self.step()?;
+            return Ok(None);
+        }
+        let pc = self.cpu.pc();
+        let opcode = self.peek(pc);
+        let running = self.cpu.is_running();
+        if running {
+            if !first && self.debugger.breaks_at(pc, self.bus.rom_bank_at(pc)) {
+                self.debugger.cancel_step();
+                return Ok(Some(BreakReason::Breakpoint(pc)));
+            }
+            if self.debugger.before(pc, self.cpu.sp(), opcode, first) {
+                return Ok(Some(BreakReason::Step));
+            }
+        }
+        self.bus.take_watch_hit();
+        self.step()?;
+        if let Some(hit) = self.bus.take_watch_hit() {
+            self.debugger.cancel_step();
+            return Ok(Some(BreakReason::Watchpoint(hit)));
+        }
+        let depth = self.cpu.calls().frames().len();
+        if running && self.debugger.after(opcode, self.cpu.sp(), depth) {
+            return Ok(Some(BreakReason::Step));
+        }
+        Ok(None)
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.debugger.add_breakpoint(breakpoint);
+    }
+
+    // Breaks at a label from the loaded symbols. Labels in switchable ROM
+    // only break in their own bank.
+    pub fn add_breakpoint_at(&mut self, label: &str) -> Option<Breakpoint> {
+        let symbol = self.symbols.find(label)?;
+        let breakpoint = Breakpoint {
+            address: symbol.address,
+            bank: match symbol.address {
+                0x4000..=0x7FFF => Some(symbol.bank),
+                _ => None,
+            },
+        };
+        self.debugger.add_breakpoint(breakpoint);
+        Some(breakpoint)
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        self.debugger.remove_breakpoint(breakpoint)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        let watchpoints = self.bus.watchpoints();
+        if !watchpoints.contains(&watchpoint) {
+            watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let watchpoints = self.bus.watchpoints();
+        let len = watchpoints.len();
+        watchpoints.retain(|w| *w != watchpoint);
+        watchpoints.len() != len
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.bus.watchpoints().clear();
+    }
+
+    // Runs a single CPU step: one instruction, one interrupt dispatch, or
+    // one M-cycle while halted or stopped.
+    pub fn step_into(&mut self) -> Result<RunSummary, EmuError> {
+        let start_frames = self.frames();
+        let start_cycles = self.cycles();
+        self.bus.take_watch_hit();
+        self.step()?;
+        let reason = match self.bus.take_watch_hit() {
+            Some(hit) => BreakReason::Watchpoint(hit),
+            None => BreakReason::Step,
+        };
+        Ok(self.summary(start_frames, self.cycles() - start_cycles, reason))
+    }
+
+    // Steps over a CALL or RST by running until it returns. The step-style
+    // runs below go on for at most a frame; if they end for another reason
+    // the step stays pending and a later run_frame or run_cycles finishes it
+    // with BreakReason::Step.
+    pub fn step_over(&mut self) -> Result<RunSummary, EmuError> {
+        let pc = self.cpu.pc();
+        let length = self.disassemble(pc).length;
+        match self.cpu.is_running()
+            && self
+                .debugger
+                .step_over(pc, self.cpu.sp(), self.peek(pc), length)
+        {
+            true => self.run_frame(),
+            false => self.step_into(),
+        }
+    }
+
+    // Runs until the current function has returned to its caller.
+    pub fn step_out(&mut self) -> Result<RunSummary, EmuError> {
+        let depth = self.cpu.calls().frames().len();
+        self.debugger.step_out(self.cpu.sp(), depth);
+        self.run_frame()
+    }
+
+    // Runs until the current function is about to return.
+    pub fn run_until_return(&mut self) -> Result<RunSummary, EmuError> {
+        self.debugger.run_until_return(self.cpu.sp());
+        self.run_frame()
+    }
+
+    pub fn cancel_step(&mut self) {
+        self.debugger.cancel_step();
+    }
+
+    fn summary(&self, start_frames: u64, cycles: u64, reason: BreakReason) -> RunSummary {
+        RunSummary {
+            frames: self.frames() - start_frames,
+            cycles,
+            reason,
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.bus.scheduler().now()
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.gpu.borrow().frames()
+    }
+
+    // Makes every CPU bus access happen at its own M-cycle rather than all
+    // at the start of the instruction. Slower, but needed by timing tests.
+    pub fn set_cycle_accurate(&mut self, accurate: bool) {
+        self.cpu.set_accurate(accurate);
+    }
+
+    // Makes LY read $90 whatever line is being drawn, so a trace can be
+    // diffed against gameboy-doctor reference logs. Games that wait for a
+    // line other than 144 will hang.
+    pub fn set_doctor_mode(&mut self, doctor: bool) {
+        self.gpu.borrow_mut().set_doctor_mode(doctor);
+    }
+
+    // True once the CPU has hit an undefined opcode. The rest of the system
+    // keeps running, as it does on hardware.
+    pub fn is_locked_up(&self) -> bool {
+        self.cpu.is_locked()
+    }
+
+    // Decodes the instruction at `address` as the CPU would see it, without
+    // advancing time. Addresses with a label are shown by name.
+    pub fn disassemble(&self, address: u16) -> Disassembly {
+        let bytes = [0, 1, 2].map(|offset| self.peek(address.wrapping_add(offset)));
+        disasm::disassemble_with_symbols(&bytes, address, self.rom_bank(), &self.symbols).unwrap()
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // `address` as a label and offset if there is one, otherwise with the
+    // ROM bank currently mapped there, e.g. `02:4A3F`.
+    pub fn describe(&self, address: u16) -> String {
+        self.symbols.describe(self.rom_bank(), address)
+    }
+
+    // The ROM bank mapped at $4000-$7FFF.
+    fn rom_bank(&self) -> u16 {
+        self.bus.rom_bank_at(0x4000).unwrap()
+    }
+
+    // Calls `hook` before every instruction the CPU runs. Halted cycles and
+    // interrupt dispatches are not instructions and are not traced.
+    pub fn set_trace<F>(&mut self, hook: F)
+    where
+        F: FnMut(&TraceEntry) + 'static,
+    {
+        self.cpu.set_trace(Some(Box::new(hook)));
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.cpu.set_trace(None);
+    }
+
+    // Starts counting the cycles each instruction takes, against its address
+    // and the call stack. Replaces any profile already being taken.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn disable_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // Records accesses by the CPU to the ranges in `config`, with the frame,
+    // line and dot they happened at. Replaces any current recorder.
+    pub fn enable_recorder(&mut self, config: RecorderConfig) {
+        self.bus.set_frame(self.frames());
+        self.bus.set_recorder(Some(AccessRecorder::new(config)));
+    }
+
+    pub fn disable_recorder(&mut self) -> Option<AccessRecorder> {
+        self.bus.set_recorder(None)
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        self.bus
+            .recorder()
+            .map_or_else(Vec::new, AccessRecorder::take_accesses)
+    }
+
+    pub fn take_heatmaps(&mut self) -> Vec<Heatmap> {
+        self.bus
+            .recorder()
+            .map_or_else(Vec::new, AccessRecorder::take_heatmaps)
+    }
+
+    // The oldest frame recorded that has been completed, if any. Calling it
+    // after every `run_frame` keeps the recorder from filling up.
+    pub fn take_frame(&mut self) -> Option<(Heatmap, Vec<Access>)> {
+        let frames = self.frames();
+        self.bus.recorder()?.take_frame(frames)
+    }
+
+    // Starts a code/data log over the whole ROM, replacing any current one.
+    pub fn enable_cdl(&mut self) {
+        let size = self.cartrigde.borrow().rom().len();
+        self.bus.set_cdl(Some(CodeDataLog::new(size)));
+    }
+
+    // Continues logging into `cdl`, e.g. one loaded from an earlier session.
+    pub fn set_cdl(&mut self, cdl: CodeDataLog) {
+        self.bus.set_cdl(Some(cdl));
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.bus.cdl()
+    }
+
+    pub fn disable_cdl(&mut self) -> Option<CodeDataLog> {
+        self.bus.set_cdl(None)
+    }
+
+    // The shadow call stack, innermost last. Calls made before the last
+    // state load are not on it.
+    pub fn call_stack(&self) -> &[Frame] {
+        self.cpu.calls().frames()
+    }
+
+    // Returns and calls the call stack could not match up. Only the most
+    // recent are kept.
+    pub fn stack_warnings(&self) -> &[StackWarning] {
+        self.cpu.calls().warnings()
+    }
+
+    pub fn clear_stack_warnings(&mut self) {
+        self.cpu.calls_mut().clear_warnings();
+    }
+
+    // The last instructions executed, oldest first.
+    pub fn history(&self) -> Vec<Location> {
+        self.cpu.history().iter().copied().collect()
+    }
+
+    pub fn set_history_len(&mut self, len: usize) {
+        self.cpu.history_mut().set_capacity(len);
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn set_cpu_state(&mut self, state: CpuState) {
+        self.cpu.set_state(state);
+    }
+
+    // Host access to memory. Unlike CPU accesses these never fault, switch
+    // banks, start a DMA or raise an interrupt, and do not take any time.
+    // The ROM can only be read.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.bus.peek(address)
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) -> Result<(), ErrorKind> {
+        if address < 0x8000 {
+            return Err(ErrorKind::ReadOnly { address });
+        }
+        self.bus.poke(address, value);
+        Ok(())
     }
 
     pub fn is_active(&mut self) -> bool {
         self.hardware.get().borrow_mut().is_active()
     }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        // Bring the lazily updated PPU up to date so its state is complete.
+        self.gpu.borrow_mut().sync(self.bus.scheduler());
+        let scheduler = self.bus.scheduler();
+        let mut w = StateWriter::new(self.cartrigde.borrow().rom_hash());
+        w.u64(scheduler.now());
+        w.section(b"CPU ", &self.cpu);
+        w.section(b"BUS ", &self.bus);
+        w.section(b"CART", &*self.cartrigde.borrow());
+        w.section(b"PPU ", &*self.gpu.borrow());
+        w.section(b"DMA ", &*self.dma.borrow());
+        w.section(b"TIME", &*self.clock.borrow());
+        w.section(b"PAD ", &*self.input.borrow());
+        w.finish()
+    }
+
+    // Covers everything a save state does: registers, RAM, VRAM, OAM, device
+    // registers and the frame buffer. Nothing outside the ROM, the start
+    // state and the input feeds into emulation, so equal runs hash equal.
+    pub fn state_hash(&self) -> u64 {
+        state::hash64(&self.save_state())
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        // Validate the header before touching anything, and roll back to the
+        // current state if a section turns out to be bad half way through.
+        let mut r = StateReader::new(data, self.cartrigde.borrow().rom_hash())?;
+        let backup = self.save_state();
+        match self.load_sections(&mut r) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let mut r = StateReader::new(&backup, self.cartrigde.borrow().rom_hash())?;
+                self.load_sections(&mut r)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn load_sections(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let now = r.u64()?;
+        r.section(b"CPU ", &mut self.cpu)?;
+        r.section(b"BUS ", &mut self.bus)?;
+        r.section(b"CART", &mut *self.cartrigde.borrow_mut())?;
+        r.section(b"PPU ", &mut *self.gpu.borrow_mut())?;
+        r.section(b"DMA ", &mut *self.dma.borrow_mut())?;
+        r.section(b"TIME", &mut *self.clock.borrow_mut())?;
+        r.section(b"PAD ", &mut *self.input.borrow_mut())?;
+        r.finish()?;
+
+        self.bus.set_rom_bank(self.cartrigde.borrow().rom_bank());
+        let scheduler = self.bus.scheduler();
+        scheduler.set_now(now);
+        {
+            let mut gpu = self.gpu.borrow_mut();
+            gpu.resync(scheduler);
+            gpu.set_stopped(self.cpu.is_stopped(), scheduler);
+        }
+        self.clock.borrow_mut().resync(scheduler);
+        match self.dma.borrow().is_active() {
+            true => scheduler.schedule(Event::Dma, now),
+            false => scheduler.cancel(Event::Dma),
+        }
+        // Queued input belongs to the timeline that was just left.
+        self.input.borrow_mut().clear_queue();
+        scheduler.cancel(Event::Joypad);
+        self.debugger.cancel_step();
+        // The call stack and history belong to the timeline that was left.
+        let sp = self.cpu.sp();
+        self.cpu.calls_mut().reset(sp);
+        self.cpu.history_mut().clear();
+        self.bus.set_frame(self.frames());
+        Ok(())
+    }
+
+    pub fn enable_rewind(&mut self, config: RewindConfig) {
+        let mut rewind = Rewind::new(config);
+        rewind.record_keys(self.cycles(), self.input.borrow().state());
+        rewind.record_frame(self.frames(), self.cycles(), self.save_state());
+        self.rewind = Some(rewind);
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    // Steps back `frames` frames (fewer if the buffer does not reach that far)
+    // and returns how many frames were actually rewound.
+    pub fn rewind(&mut self, frames: u64) -> Result<u64, StateError> {
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return Ok(0),
+        };
+        let current = self.frames();
+        let target = current.saturating_sub(frames);
+        let state = match rewind.restore(target) {
+            Some(state) => state,
+            None => {
+                self.rewind = Some(rewind);
+                return Ok(0);
+            }
+        };
+        if let Err(e) = self.load_state(&state) {
+            self.rewind = Some(rewind);
+            return Err(e);
+        }
+        let target = target.max(self.frames());
+        rewind.start_replay();
+        let mut keys = self.input.borrow().state();
+        for (cycle, next) in rewind.keys_after(self.cycles()) {
+            for button in Button::ALL {
+                if keys.is_pressed(button) != next.is_pressed(button) {
+                    self.queue_input(InputEvent {
+                        cycle: *cycle,
+                        button,
+                        pressed: next.is_pressed(button),
+                    });
+                }
+            }
+            keys = *next;
+        }
+        self.rewind = Some(rewind);
+        // These frames already ran once, so an error here means the replay
+        // went wrong; it is reported with the System left where it stopped.
+        let mut result = Ok(());
+        while self.frames() < target {
+            if let Err(e) = self.step() {
+                result = Err(StateError::Replay(e.kind));
+                break;
+            }
+        }
+        self.input.borrow_mut().clear_queue();
+        let mut rewind = self.rewind.take().unwrap();
+        rewind.finish_replay(self.cycles());
+        self.rewind = Some(rewind);
+        let rewound = current - self.frames();
+        let now = self.cycles();
+        let keys = self.input.borrow().state();
+        match &mut self.movie {
+            // Everything recorded so far happened after the rewound-to point.
+            Some(session) if session.is_recording() && now < session.start() => {
+                self.start_recording()
+            }
+            Some(session) => session.rewind(rewound as usize, now, keys),
+            None => {}
+        }
+        self.schedule_joypad();
+        self.hardware
+            .get()
+            .borrow_mut()
+            .draw_framebuffer(self.gpu.borrow().frame_buffer());
+        result.map(|_| rewound)
+    }
+
+    fn is_replaying(&self) -> bool {
+        match &self.rewind {
+            Some(rewind) => rewind.is_replaying(),
+            None => false,
+        }
+    }
 }
 
-pub fn run<T>(cart: Cartridge, hardware: T)
+pub fn run<T>(cart: Cartridge, hardware: T) -> Result<(), EmuError>
 where
     T: Hardware + 'static,
 {
     let mut system = System::new(cart, hardware);
 
     while system.is_active() {
-        system.step();
+        system.run_frame()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cdl::{CDL_CODE, CDL_DATA, CDL_OPERAND};
+    use crate::debug::{FrameKind, WatchKind};
+    use crate::profile::Grouping;
+    use alloc::{rc::Rc, string::ToString, vec};
+    use core::cell::{Cell, RefCell};
+
+    pub struct NullHardware;
+
+    impl Hardware for NullHardware {
+        fn is_active(&mut self) -> bool {
+            true
+        }
+        fn draw_framebuffer(&mut self, _frame_buffer: &FrameBuffer) {}
+        fn update(&mut self) {}
+    }
+
+    // 32KiB ROM with `program` at the entry point.
+    pub fn rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    pub fn system(program: &[u8]) -> System {
+        System::new(Cartridge::new(rom(program), vec![0; 0x2000]), NullHardware)
+    }
+
+    // ld hl, $C000; loop: inc [hl]; inc hl; jr loop
+    const COUNTER: [u8; 6] = [0x21, 0x00, 0xC0, 0x34, 0x23, 0x18];
+
+    fn counter() -> System {
+        let mut program = COUNTER.to_vec();
+        program.push(-4i8 as u8);
+        system(&program)
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut system = counter();
+        for _ in 0..1000 {
+            system.step().unwrap();
+        }
+        let state = system.save_state();
+        for _ in 0..1000 {
+            system.step().unwrap();
+        }
+        let later = system.save_state();
+        assert_ne!(state, later);
+
+        system.load_state(&state).unwrap();
+        assert_eq!(system.save_state(), state);
+        for _ in 0..1000 {
+            system.step().unwrap();
+        }
+        assert_eq!(system.save_state(), later);
+    }
+
+    #[test]
+    fn reject_foreign_state() {
+        let mut counter = counter();
+        let state = counter.save_state();
+        let mut other = system(&[0x00]);
+        assert_eq!(other.load_state(&state), Err(StateError::RomMismatch));
+
+        let before = counter.save_state();
+        let truncated = &state[..state.len() - 16];
+        assert_eq!(counter.load_state(truncated), Err(StateError::Truncated));
+        assert_eq!(counter.save_state(), before);
+    }
+
+    fn run_to_frame(system: &mut System, frame: u64) {
+        while system.frames() < frame {
+            system.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn rewind_replays_to_frame() {
+        let mut system = counter();
+        system.enable_rewind(RewindConfig {
+            interval: 2,
+            capacity: 8,
+        });
+        // Between the snapshot at frame 6 and frame 7, so replay needs it.
+        system.queue_input(InputEvent {
+            cycle: 450_000,
+            button: Button::Start,
+            pressed: true,
+        });
+        run_to_frame(&mut system, 7);
+        let at_seven = system.save_state();
+        run_to_frame(&mut system, 10);
+        for _ in 0..100 {
+            system.step().unwrap();
+        }
+
+        assert_eq!(system.rewind(3), Ok(3));
+        assert_eq!(system.frames(), 7);
+        assert_eq!(system.save_state(), at_seven);
+    }
+
+    #[test]
+    fn rewind_past_buffer_goes_to_oldest() {
+        let mut system = counter();
+        system.enable_rewind(RewindConfig {
+            interval: 2,
+            capacity: 2,
+        });
+        run_to_frame(&mut system, 8);
+        let at_eight = system.save_state();
+        run_to_frame(&mut system, 10);
+        for _ in 0..100 {
+            system.step().unwrap();
+        }
+
+        // Only the snapshots at frames 8 and 10 are left.
+        assert_eq!(system.rewind(100), Ok(2));
+        assert_eq!(system.frames(), 8);
+        assert_eq!(system.save_state(), at_eight);
+    }
+
+    struct Keys(Rc<Cell<JoypadState>>, Rc<Cell<u32>>);
+
+    impl Hardware for Keys {
+        fn is_active(&mut self) -> bool {
+            true
+        }
+        fn draw_framebuffer(&mut self, _frame_buffer: &FrameBuffer) {}
+        fn get_keys(&mut self) -> Option<JoypadState> {
+            self.1.set(self.1.get() + 1);
+            Some(self.0.get())
+        }
+        fn update(&mut self) {}
+    }
+
+    #[test]
+    fn host_input_polled_every_step() {
+        // ld a, $10; ldh [$00], a; loop: ldh a, [$00]; ld [$C000], a; jr loop
+        let program = [
+            0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xEA, 0x00, 0xC0, 0x18, 0xF9,
+        ];
+        let keys = Rc::new(Cell::new(JoypadState::new()));
+        let polls = Rc::new(Cell::new(0));
+        let cart = Cartridge::new(rom(&program), vec![0; 0x2000]);
+        let mut system = System::new(cart, Keys(keys.clone(), polls.clone()));
+        for _ in 0..5 {
+            system.step().unwrap();
+        }
+        assert_eq!(polls.get(), 5);
+        assert_eq!(system.peek(0xC000) & 0x0F, 0x0F);
+
+        // A press is on the pad as soon as the step it was polled in ends,
+        // and the next read of P1 sees it.
+        let mut pressed = JoypadState::new();
+        pressed.press(Button::A);
+        keys.set(pressed);
+        system.step().unwrap();
+        assert!(system.input.borrow().state().is_pressed(Button::A));
+        while system.cpu.pc() != 0x0104 {
+            system.step().unwrap();
+        }
+        system.step().unwrap();
+        system.step().unwrap();
+        assert_eq!(system.peek(0xC000) & 0x0F, 0x0E);
+    }
+
+    #[test]
+    fn queued_input_applies_at_cycle() {
+        for recording in [false, true] {
+            let mut system = counter();
+            if recording {
+                system.start_recording();
+            }
+            system.queue_input(InputEvent {
+                cycle: 1000,
+                button: Button::A,
+                pressed: true,
+            });
+            while system.cycles() < 1000 {
+                assert!(!system.input.borrow().state().is_pressed(Button::A));
+                system.step().unwrap();
+            }
+            assert!(system.input.borrow().state().is_pressed(Button::A));
+        }
+    }
+
+    // ld hl, $C000; loop: inc [hl]; inc l; jr loop
+    const PAGE_COUNTER: [u8; 7] = [0x21, 0x00, 0xC0, 0x34, 0x2C, 0x18, 0xFC];
+
+    fn record_movie() -> (Movie, Vec<u8>) {
+        let mut system = system(&PAGE_COUNTER);
+        system.run_frame().unwrap();
+        system.start_recording();
+        for (frame, button) in [(3, Button::A), (60, Button::Up), (90, Button::Start)] {
+            system.queue_input(InputEvent {
+                cycle: system.cycles() + frame * FRAME_CYCLES + 1000,
+                button,
+                pressed: true,
+            });
+        }
+        for _ in 0..130 {
+            system.run_frame().unwrap();
+        }
+        let end = system.save_state();
+        (system.stop_movie().unwrap(), end)
+    }
+
+    #[test]
+    fn movie_plays_back() {
+        let (movie, end) = record_movie();
+        assert_eq!(movie.len(), 130);
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+
+        let mut system = system(&PAGE_COUNTER);
+        system.play_movie(movie).unwrap();
+        for _ in 0..130 {
+            system.run_frame().unwrap();
+        }
+        let status = system.movie_status().unwrap();
+        assert_eq!(status.frame, 130);
+        assert_eq!(status.desync, None);
+        assert_eq!(system.save_state(), end);
+    }
+
+    #[test]
+    fn rewind_while_recording() {
+        let mut system = system(&PAGE_COUNTER);
+        system.run_frame().unwrap();
+        system.enable_rewind(RewindConfig {
+            interval: 4,
+            capacity: 8,
+        });
+        system.start_recording();
+        let start = system.cycles();
+        for (frame, button) in [(3, Button::A), (25, Button::Up), (35, Button::Start)] {
+            system.queue_input(InputEvent {
+                cycle: start + frame * FRAME_CYCLES + 1000,
+                button,
+                pressed: true,
+            });
+        }
+        for _ in 0..30 {
+            system.run_frame().unwrap();
+        }
+        // The Up press is undone, and Start was never applied.
+        assert_eq!(system.rewind(10), Ok(10));
+        assert!(!system.input.borrow().state().is_pressed(Button::Up));
+        system.queue_input(InputEvent {
+            cycle: system.cycles() + 5 * FRAME_CYCLES,
+            button: Button::B,
+            pressed: true,
+        });
+        for _ in 0..20 {
+            system.run_frame().unwrap();
+        }
+        let end = system.save_state();
+        let movie = system.stop_movie().unwrap();
+        assert_eq!(movie.len(), 40);
+        let events: Vec<Button> = (0..)
+            .map_while(|i| movie.event(i))
+            .map(|e| e.button)
+            .collect();
+        assert_eq!(events, [Button::A, Button::B]);
+
+        let mut system = self::system(&PAGE_COUNTER);
+        system.play_movie(movie).unwrap();
+        for _ in 0..40 {
+            system.run_frame().unwrap();
+        }
+        assert_eq!(system.movie_status().unwrap().desync, None);
+        assert_eq!(system.save_state(), end);
+    }
+
+    #[test]
+    fn input_returns_after_movie_ends() {
+        let (movie, _) = record_movie();
+        let mut system = system(&PAGE_COUNTER);
+        system.play_movie(movie).unwrap();
+        for _ in 0..131 {
+            system.run_frame().unwrap();
+        }
+        let status = system.movie_status().unwrap();
+        assert!(status.finished);
+        assert_eq!(status.frame, 130);
+
+        system.queue_input(InputEvent {
+            cycle: system.cycles() + 1000,
+            button: Button::B,
+            pressed: true,
+        });
+        system.run_frame().unwrap();
+        assert!(system.input.borrow().state().is_pressed(Button::B));
+    }
+
+    #[test]
+    fn movie_detects_desync() {
+        let (movie, _) = record_movie();
+        // Drop the Up press latched at frame 60, where a hash was taken.
+        let mut data = movie.to_bytes();
+        let frames = 4 + 2 + 4 + 4 + movie.start().len() + 4;
+        data[frames + 60] &= !0x04;
+        let movie = Movie::from_bytes(&data).unwrap();
+
+        let mut system = system(&PAGE_COUNTER);
+        system.play_movie(movie).unwrap();
+        for _ in 0..130 {
+            system.run_frame().unwrap();
+        }
+        assert_eq!(system.movie_status().unwrap().desync, Some(60));
+    }
+
+    #[test]
+    fn state_hash_is_deterministic() {
+        let run = |button| {
+            let mut system = system(&PAGE_COUNTER);
+            system.queue_input(InputEvent {
+                cycle: 100_000,
+                button,
+                pressed: true,
+            });
+            for _ in 0..5 {
+                system.run_frame().unwrap();
+            }
+            system.state_hash()
+        };
+        assert_eq!(run(Button::A), run(Button::A));
+        assert_ne!(run(Button::A), run(Button::B));
+    }
+
+    #[test]
+    fn disassemble_from_bus() {
+        let mut system = counter();
+        assert_eq!(system.disassemble(0x0100).text, "ld hl, $C000");
+        assert_eq!(system.disassemble(0x0105).text, "jr $0103");
+        assert_eq!(system.disassemble(0x0105).cycles, 12);
+        system.step().unwrap();
+        assert_eq!(system.cycles(), 12);
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut system = counter();
+        system.add_breakpoint(Breakpoint {
+            address: 0x0104,
+            bank: Some(1),
+        });
+        assert_eq!(system.run_frame().unwrap().reason, BreakReason::Frame);
+
+        let mut system = counter();
+        let breakpoint = Breakpoint {
+            address: 0x0104,
+            bank: None,
+        };
+        system.add_breakpoint(breakpoint);
+        let summary = system.run_frame().unwrap();
+        assert_eq!(summary.reason, BreakReason::Breakpoint(0x0104));
+        assert_eq!(summary.cycles, 24);
+        // Running again moves past the breakpoint and comes back round.
+        let summary = system.run_frame().unwrap();
+        assert_eq!(summary.reason, BreakReason::Breakpoint(0x0104));
+        assert_eq!(summary.cycles, 32);
+
+        assert!(system.remove_breakpoint(breakpoint));
+        system.add_watchpoint(Watchpoint {
+            start: 0xC002,
+            end: 0xC003,
+            kind: WatchKind::Write,
+            value: Some(1),
+        });
+        let summary = system.run_frame().unwrap();
+        assert_eq!(
+            summary.reason,
+            BreakReason::Watchpoint(WatchHit {
+                address: 0xC002,
+                value: 1,
+                write: true,
+            })
+        );
+        assert_eq!(system.cpu.pc(), 0x0104);
+    }
+
+    fn calls() -> System {
+        // call $0110; loop: inc b; jr loop
+        let mut program = vec![0xCD, 0x10, 0x01, 0x04, 0x18, 0xFE];
+        program.resize(0x10, 0);
+        // inc c; call $0118; ret; ...; ret
+        program.extend([0x0C, 0xCD, 0x18, 0x01, 0xC9, 0, 0, 0, 0xC9]);
+        system(&program)
+    }
+
+    #[test]
+    fn step_over_out_and_until_return() {
+        let mut system = calls();
+        let summary = system.step_over().unwrap();
+        assert_eq!(summary.reason, BreakReason::Step);
+        assert_eq!(system.cpu.pc(), 0x0103);
+
+        let mut system = calls();
+        system.step_into().unwrap();
+        assert_eq!(system.cpu.pc(), 0x0110);
+        system.step_into().unwrap();
+        assert_eq!(system.run_until_return().unwrap().reason, BreakReason::Step);
+        assert_eq!(system.cpu.pc(), 0x0114);
+
+        let mut system = calls();
+        system.step_into().unwrap();
+        system.step_into().unwrap();
+        assert_eq!(system.step_out().unwrap().reason, BreakReason::Step);
+        assert_eq!(system.cpu.pc(), 0x0103);
+    }
+
+    #[test]
+    fn step_out_waits_for_own_return() {
+        // call $0110; loop: inc b; jr loop
+        let mut program = vec![0xCD, 0x10, 0x01, 0x04, 0x18, 0xFE];
+        program.resize(0x10, 0);
+        // push bc; pop bc; call $0118; ret; ...; ret
+        program.extend([0xC5, 0xC1, 0xCD, 0x18, 0x01, 0xC9, 0, 0, 0xC9]);
+        let mut system = system(&program);
+        system.step_into().unwrap();
+        system.step_into().unwrap();
+        // The nested return leaves SP above where it was here, but the
+        // routine has not returned yet.
+        assert_eq!(system.cpu.sp(), 0xFFFA);
+        assert_eq!(system.step_out().unwrap().reason, BreakReason::Step);
+        assert_eq!(system.cpu.pc(), 0x0103);
+    }
+
+    #[test]
+    fn host_state_access() {
+        let mut system = system(&[0x00]);
+        let mut state = system.cpu_state();
+        assert_eq!(state.pc, 0x0100);
+        state.b = 0x41;
+        state.f = 0xFF;
+        state.pc = 0xC000;
+        system.set_cpu_state(state);
+        // inc b
+        system.poke(0xC000, 0x04).unwrap();
+        system.step().unwrap();
+        let state = system.cpu_state();
+        assert_eq!((state.b, state.f, state.pc), (0x42, 0x10, 0xC001));
+
+        // None of these have their usual side effects.
+        system.poke(0xFF46, 0xC0).unwrap();
+        assert_eq!(system.bus.scheduler().deadline(Event::Dma), None);
+        system.poke(0x8000, 0x5A).unwrap();
+        assert_eq!(system.peek(0x8000), 0x5A);
+        assert_eq!(system.peek(0xFF46), 0x00);
+        system.poke(0xA010, 0x77).unwrap();
+        assert_eq!(system.peek(0xA010), 0x77);
+        assert_eq!(system.cycles(), 4);
+
+        // The ROM, and so the mapper registers behind it, cannot be poked.
+        let hash = system.state_hash();
+        assert_eq!(
+            system.poke(0x0100, 0x04),
+            Err(ErrorKind::ReadOnly { address: 0x0100 })
+        );
+        assert_eq!(
+            system.poke(0x2000, 0x02).unwrap_err().to_string(),
+            "cannot poke ROM at 2000"
+        );
+        assert_eq!(system.peek(0x0100), 0x00);
+        assert_eq!(system.bus.rom_bank_at(0x4000), Some(1));
+        assert_eq!(system.state_hash(), hash);
+    }
+
+    #[test]
+    fn trace_in_gameboy_doctor_format() {
+        // loop: inc a; jr loop
+        let mut system = system(&[0x3C, 0x18, 0xFD]);
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let sink = lines.clone();
+        system.set_trace(move |entry| sink.borrow_mut().push(entry.to_string()));
+        system.step().unwrap();
+        system.step().unwrap();
+        system.clear_trace();
+        system.step().unwrap();
+        assert_eq!(
+            *lines.borrow(),
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3C,18,FD,00",
+                "A:02 F:10 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:18,FD,00,00",
+            ]
+        );
+    }
+
+    #[test]
+    fn symbols_in_debug_output() {
+        let mut system = counter();
+        system.set_symbols(Symbols::parse("00:0103 Loop\n00:C000 wCount\n"));
+        assert_eq!(system.add_breakpoint_at("Missing"), None);
+        assert!(system.add_breakpoint_at("Loop").is_some());
+        let reason = system.run_frame().unwrap().reason;
+        assert_eq!(reason, BreakReason::Breakpoint(0x0103));
+        assert_eq!(system.describe(0x0103), "Loop");
+        assert_eq!(system.describe(0x0104), "Loop+$1");
+        assert_eq!(system.describe(0x4A3F), "01:4A3F");
+        assert_eq!(system.disassemble(0x0100).text, "ld hl, $C000");
+        assert_eq!(system.disassemble(0x0105).text, "jr Loop");
+    }
+
+    #[test]
+    fn cdl_is_bank_aware() {
+        // ld a, [$4000]; ld [$2000], a; ld a, [$4000]; halt
+        let mut rom = rom(&[0xFA, 0x00, 0x40, 0xEA, 0x00, 0x20, 0xFA, 0x00, 0x40, 0x76]);
+        rom.resize(0x10000, 0);
+        rom[0x4000] = 2;
+        let mut system = System::new(Cartridge::new(rom, vec![0; 0x2000]), NullHardware);
+        system.enable_cdl();
+        for _ in 0..4 {
+            system.step().unwrap();
+        }
+        let cdl = system.disable_cdl().unwrap();
+        assert_eq!(cdl.len(), 0x10000);
+        assert_eq!(cdl.flags(0x100), CDL_CODE);
+        assert_eq!(cdl.flags(0x101), CDL_OPERAND);
+        assert_eq!(cdl.to_bytes()[0x101], CDL_CODE);
+        assert_eq!(cdl.flags(0x109), CDL_CODE);
+        assert_eq!(cdl.flags(0x4000), CDL_DATA);
+        assert_eq!(cdl.flags(0x8000), CDL_DATA);
+        assert_eq!(cdl.coverage().code, 4);
+        assert_eq!(cdl.coverage().covered, 12);
+    }
+
+    #[test]
+    fn shadow_call_stack() {
+        // call $0108; call $010A; jr @; $0108: ret; nop; $010A: pop hl; ret
+        let program = [
+            0xCD, 0x08, 0x01, 0xCD, 0x0A, 0x01, 0x18, 0xFE, 0xC9, 0x00, 0xE1, 0xC9,
+        ];
+        let mut system = system(&program);
+        system.step().unwrap();
+        system.step().unwrap();
+        assert!(system.call_stack().is_empty());
+        system.step().unwrap();
+        let at = |address| Location { bank: 0, address };
+        assert_eq!(
+            system.call_stack(),
+            [Frame {
+                kind: FrameKind::Call,
+                site: at(0x0103),
+                target: at(0x010A),
+                return_address: 0x0106,
+                sp: 0xFFFC,
+            }]
+        );
+        assert!(system.stack_warnings().is_empty());
+        system.step().unwrap();
+        system.step().unwrap();
+        let warnings = system.stack_warnings();
+        assert_eq!(
+            warnings[0],
+            StackWarning::Abandoned {
+                at: at(0x010B),
+                frames: 1
+            }
+        );
+        assert!(matches!(warnings[1], StackWarning::UnmatchedReturn { .. }));
+        let history: Vec<u16> = system.history().iter().map(|l| l.address).collect();
+        assert_eq!(history, [0x0100, 0x0108, 0x0103, 0x010A, 0x010B]);
+    }
+
+    #[test]
+    fn error_reports_call_stack() {
+        // call $0110; $0110: ld a, 3; ld [$2000], a; ld a, [$4000]
+        let mut program = vec![0xCD, 0x10, 0x01];
+        program.resize(0x10, 0);
+        program.extend([0x3E, 0x03, 0xEA, 0x00, 0x20, 0xFA, 0x00, 0x40]);
+        let mut system = system(&program);
+        let err = loop {
+            if let Err(err) = system.step() {
+                break err;
+            }
+        };
+        assert_eq!(err.pc, 0x0115);
+        assert_eq!(err.call_stack.len(), 1);
+        assert_eq!(err.history.len(), 4);
+        assert_eq!(
+            err.to_string(),
+            "ROM read at 4000 is past the end of the ROM (offset 00C000) (PC=0115)\n  \
+             in call 00:0110 from 00:0100\n  \
+             recent: 00:0100 00:0110 00:0112 00:0115"
+        );
+    }
+
+    #[test]
+    fn profile_by_call() {
+        // loop: call $0108; jr loop; $0108: nop; ret
+        let mut system = system(&[0xCD, 0x08, 0x01, 0x18, 0xFB, 0x00, 0x00, 0x00, 0x00, 0xC9]);
+        system.set_symbols(Symbols::parse("00:0100 Main\n00:0108 Sub\n"));
+        system.enable_profiler();
+        for _ in 0..40 {
+            system.step().unwrap();
+        }
+        let profiler = system.disable_profiler().unwrap();
+        // call 24 + jr 12 in Main and nop 4 + ret 16 in Sub, ten times over.
+        assert_eq!(profiler.total(), 560);
+        assert_eq!(
+            profiler.collapsed(system.symbols()),
+            "(root) 360\n(root);Sub 200\n"
+        );
+        let report = profiler.report(Grouping::Symbol, system.symbols());
+        assert_eq!(report[0].name, "Main");
+        assert_eq!(report[0].cycles, 360);
+        assert_eq!(report[1].name, "Sub");
+    }
+
+    #[test]
+    fn profile_charges_dispatch_to_handler() {
+        // ei; nop; loop: jr loop; $0050: reti
+        let mut rom = rom(&[0xFB, 0x00, 0x18, 0xFE]);
+        rom[0x50] = 0xD9;
+        let mut system = System::new(Cartridge::new(rom, vec![0; 0x2000]), NullHardware);
+        system.set_symbols(Symbols::parse("00:0050 Timer\n"));
+        system.poke(0xFFFF, 0x04).unwrap();
+        system.enable_profiler();
+        system.step().unwrap();
+        system.step().unwrap();
+        system.bus.set_if(0x04);
+        for _ in 0..3 {
+            system.step().unwrap();
+        }
+        let profiler = system.profiler().unwrap();
+        // Dispatch 20 and reti 16 in the handler; ei 4, nop 4 and jr 12 outside.
+        assert_eq!(
+            profiler.collapsed(system.symbols()),
+            "(root) 20\n(root);Timer 36\n"
+        );
+        let report = profiler.report(Grouping::Pc, system.symbols());
+        assert_eq!((report[0].name.as_str(), report[0].cycles), ("0050", 36));
+        assert_eq!((report[1].name.as_str(), report[1].cycles), ("0102", 12));
+    }
+
+    #[test]
+    fn records_vram_writes() {
+        // Doctor mode only changes what the CPU reads from LY.
+        for doctor in [false, true] {
+            // ld hl, $8000; loop: ld [hl], a; inc a; jr loop
+            let mut system = system(&[0x21, 0x00, 0x80, 0x77, 0x3C, 0x18, 0xFC]);
+            system.set_doctor_mode(doctor);
+            system.enable_recorder(RecorderConfig::default());
+            system.run_frame().unwrap();
+            system.run_frame().unwrap();
+            let accesses = system.take_accesses();
+            let heatmaps = system.take_heatmaps();
+            assert!(system.take_accesses().is_empty());
+            assert_eq!(heatmaps.len(), 2);
+            assert_eq!(heatmaps[0].frame + 1, heatmaps[1].frame);
+            let writes: u32 = heatmaps.iter().map(|map| map.get(0x8000).writes).sum();
+            assert_eq!(writes as usize, accesses.len());
+            assert_eq!(heatmaps[1].iter().count(), 1);
+            // Each pass around the loop takes 24 cycles.
+            for pair in accesses.windows(2) {
+                let (a, b) = (pair[0], pair[1]);
+                assert_eq!((a.pc, a.address, a.write), (0x0103, 0x8000, true));
+                assert_eq!(b.value, a.value.wrapping_add(1));
+                if a.frame == b.frame {
+                    // Frames are counted from the start of vblank.
+                    let at =
+                        |access: Access| (access.ly as u32 + 10) % 154 * 456 + access.dot as u32;
+                    assert_eq!(at(b) - at(a), 24);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn records_only_the_cpu() {
+        // ld a, $C0; ldh [$46], a; loop: jr loop
+        let mut system = system(&[0x3E, 0xC0, 0xE0, 0x46, 0x18, 0xFE]);
+        system.poke(0xC000, 0x42).unwrap();
+        system.enable_recorder(RecorderConfig {
+            ranges: vec![(0xC000, 0xC09F), (0xFE00, 0xFE9F), (0xFF46, 0xFF46)],
+            reads: true,
+            ..RecorderConfig::default()
+        });
+        system.run_frame().unwrap();
+        assert_eq!(system.peek(0xFE00), 0x42);
+        // The DMA copy itself is left out.
+        let (heatmap, accesses) = system.take_frame().unwrap();
+        assert_eq!(accesses.len(), 1);
+        assert_eq!((accesses[0].pc, accesses[0].address), (0x0102, 0xFF46));
+        assert_eq!(heatmap.iter().count(), 1);
+        assert!(system.take_frame().is_none());
+    }
+
+    #[test]
+    fn run_frame_stops_at_vblank() {
+        let mut system = counter();
+        let first = system.run_frame().unwrap();
+        assert_eq!(first.reason, BreakReason::Frame);
+        assert_eq!(first.frames, 1);
+        let second = system.run_frame().unwrap();
+        assert_eq!(second.frames, 1);
+        assert!(second.cycles >= FRAME_CYCLES && second.cycles < FRAME_CYCLES + 24);
+    }
+
+    #[test]
+    fn doctor_mode_pins_ly() {
+        // ldh a, [$44]; ldh a, [$44]
+        let mut system = system(&[0xF0, 0x44, 0xF0, 0x44]);
+        system.step().unwrap();
+        assert_eq!(system.cpu_state().a, 0x00);
+        system.set_doctor_mode(true);
+        system.step().unwrap();
+        assert_eq!(system.cpu_state().a, 0x90);
+        assert_eq!(system.peek(0xFF44), 0x90);
+        system.set_doctor_mode(false);
+        assert_eq!(system.peek(0xFF44), 0x00);
+    }
+
+    #[test]
+    fn run_frame_times_out_with_lcd_off() {
+        // xor a; ldh [$40], a; loop: jr loop
+        let mut system = system(&[0xAF, 0xE0, 0x40, 0x18, 0xFE]);
+        let summary = system.run_frame().unwrap();
+        assert_eq!(summary.reason, BreakReason::LcdOff);
+        assert_eq!(summary.frames, 0);
+        assert!(summary.cycles >= FRAME_CYCLES);
+    }
+
+    #[test]
+    fn run_cycles_counts_frames() {
+        let mut system = counter();
+        let summary = system.run_cycles(3 * FRAME_CYCLES).unwrap();
+        assert_eq!(summary.reason, BreakReason::CycleBudget);
+        assert_eq!(summary.frames, 3);
+        assert!(summary.cycles >= 3 * FRAME_CYCLES);
+    }
+
+    #[test]
+    fn undefined_opcode_locks_up() {
+        // nop; nop; $D3
+        let program = [0x00, 0x00, 0xD3];
+        let mut locked = system(&program);
+        locked.run_cycles(12).unwrap();
+        assert!(locked.is_locked_up());
+        assert_eq!(locked.cpu.pc(), 0x0102);
+
+        // The PPU keeps running, and a save state keeps the lockup.
+        let summary = locked.run_frame().unwrap();
+        assert_eq!(summary.reason, BreakReason::Frame);
+        assert_eq!(locked.cpu.pc(), 0x0102);
+        let mut other = system(&program);
+        other.load_state(&locked.save_state()).unwrap();
+        assert!(other.is_locked_up());
+    }
+
+    struct HeldKeys(Rc<Cell<JoypadState>>);
+
+    impl Hardware for HeldKeys {
+        fn is_active(&mut self) -> bool {
+            true
+        }
+        fn draw_framebuffer(&mut self, _frame_buffer: &FrameBuffer) {}
+        fn get_keys(&mut self) -> Option<JoypadState> {
+            Some(self.0.get())
+        }
+        fn update(&mut self) {}
+    }
+
+    #[test]
+    fn stop_waits_for_joypad() {
+        // ld a, $20; ldh [$00], a; stop; loop: inc b; jr loop
+        let program = [0x3E, 0x20, 0xE0, 0x00, 0x10, 0x00, 0x04, 0x18, 0xFD];
+        let keys = Rc::new(Cell::new(JoypadState::new()));
+        let cart = Cartridge::new(rom(&program), vec![0; 0x2000]);
+        let mut system = System::new(cart, HeldKeys(keys.clone()));
+        system.enable_recorder(RecorderConfig {
+            ranges: vec![(0xFF00, 0xFF07)],
+            reads: true,
+            ..RecorderConfig::default()
+        });
+        for _ in 0..3 {
+            system.step().unwrap();
+        }
+        assert!(system.cpu.is_stopped());
+        // STOP checks the joypad and resets DIV itself; only the game's own
+        // write to P1 is recorded.
+        let accesses = system.take_accesses();
+        assert_eq!(accesses.len(), 1);
+        assert_eq!((accesses[0].address, accesses[0].write), (0xFF00, true));
+        assert_eq!(system.cpu.pc(), 0x0106);
+
+        let summary = system.run_frame().unwrap();
+        assert_eq!(summary.reason, BreakReason::Stopped);
+        assert_eq!(system.frames(), 0);
+        assert_eq!(system.bus.read_byte(0xFF04), Some(0));
+
+        let mut right = JoypadState::new();
+        right.press(Button::Right);
+        keys.set(right);
+        let summary = system.run_frame().unwrap();
+        assert_eq!(summary.reason, BreakReason::Frame);
+        assert!(!system.cpu.is_stopped());
+    }
+
+    #[test]
+    fn bank_past_rom_end_is_reported() {
+        // ld a, 8; ld [$2000], a; ld a, [$4000]
+        let mut system = system(&[0x3E, 0x08, 0xEA, 0x00, 0x20, 0xFA, 0x00, 0x40]);
+        system.step().unwrap();
+        system.step().unwrap();
+        let err = system.step().unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::RomOutOfRange {
+                address: 0x4000,
+                offset: 0x20000
+            }
+        );
+        assert_eq!(err.pc, 0x0105);
     }
 }